@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Result};
+use std::io::{Seek, SeekFrom, Write};
+
+/// `avih` flag set when the file ends with an `idx1` index.
+const AVIF_HASINDEX: u32 = 0x10;
+
+/// `idx1` flag set for chunks that can be decoded on their own.
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// Writer of an AVI file holding a single Motion JPEG video stream.
+///
+/// The file is a `RIFF AVI ` form with a `hdrl` list of the main and stream
+/// headers, a `movi` list with one `00dc` chunk per frame and an `idx1`
+/// index of those chunks. Sizes and frame counts that are only known at the
+/// end are written as zero and filled in by [`finish`](Self::finish).
+pub struct AviWriter<W>
+where
+    W: Write + Seek,
+{
+    out: W,
+    /// Offset and size of every frame chunk, relative to the `movi` tag.
+    chunks: Vec<(u32, u32)>,
+    movi: u64,
+    position: u64,
+}
+
+impl<W> AviWriter<W>
+where
+    W: Write + Seek,
+{
+    /// Start a file of `width` x `height` frames at `num / den` frames per
+    /// second.
+    pub fn new(mut out: W, width: usize, height: usize, frame_rate: (u32, u32)) -> Result<Self> {
+        let (num, den) = frame_rate;
+        let (width, height) = (u32::try_from(width)?, u32::try_from(height)?);
+
+        let mut avih = Vec::new();
+        for value in [
+            (1_000_000 * den as u64 / num as u64) as u32,
+            0,
+            0,
+            AVIF_HASINDEX,
+            0, // total frames
+            0,
+            1,
+            0, // suggested buffer size
+            width,
+            height,
+            0,
+            0,
+            0,
+            0,
+        ] {
+            avih.extend(value.to_le_bytes());
+        }
+
+        let mut strh = b"vidsMJPG".to_vec();
+        for value in [0, 0, 0, den, num, 0, 0, 0, u32::MAX, 0] {
+            strh.extend(value.to_le_bytes());
+        }
+        for value in [0, 0, width as u16, height as u16] {
+            strh.extend(u16::to_le_bytes(value));
+        }
+
+        // BITMAPINFOHEADER
+        let mut strf = Vec::new();
+        strf.extend(40u32.to_le_bytes());
+        strf.extend(width.to_le_bytes());
+        strf.extend(height.to_le_bytes());
+        strf.extend(1u16.to_le_bytes());
+        strf.extend(24u16.to_le_bytes());
+        strf.extend(b"MJPG");
+        strf.extend((width * height * 3).to_le_bytes());
+        strf.extend([0; 16]);
+
+        let mut strl = b"strl".to_vec();
+        append_chunk(&mut strl, b"strh", &strh);
+        append_chunk(&mut strl, b"strf", &strf);
+
+        let mut hdrl = b"hdrl".to_vec();
+        append_chunk(&mut hdrl, b"avih", &avih);
+        append_chunk(&mut hdrl, b"LIST", &strl);
+
+        let mut head = b"RIFF\0\0\0\0AVI ".to_vec();
+        append_chunk(&mut head, b"LIST", &hdrl);
+        head.extend(b"LIST\0\0\0\0movi");
+        out.write_all(&head)?;
+
+        let position = head.len() as u64;
+        Ok(AviWriter {
+            out,
+            chunks: Vec::new(),
+            movi: position - 4,
+            position,
+        })
+    }
+
+    /// Append one frame, a complete JPEG file.
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<()> {
+        let offset = u32::try_from(self.position - self.movi)?;
+        let size = u32::try_from(data.len())?;
+
+        let mut chunk = Vec::with_capacity(data.len() + 9);
+        append_chunk(&mut chunk, b"00dc", data);
+        self.out.write_all(&chunk)?;
+
+        self.chunks.push((offset, size));
+        self.position += chunk.len() as u64;
+
+        Ok(())
+    }
+
+    /// Write the index and fill in the sizes and counts of the headers.
+    pub fn finish(mut self) -> Result<()> {
+        let movi_size = u32::try_from(self.position - self.movi)?;
+
+        let mut idx1 = Vec::with_capacity(16 * self.chunks.len());
+        for &(offset, size) in &self.chunks {
+            idx1.extend(b"00dc");
+            idx1.extend(AVIIF_KEYFRAME.to_le_bytes());
+            idx1.extend(offset.to_le_bytes());
+            idx1.extend(size.to_le_bytes());
+        }
+        let mut tail = Vec::new();
+        append_chunk(&mut tail, b"idx1", &idx1);
+        self.out.write_all(&tail)?;
+
+        let riff_size = u32::try_from(self.position + tail.len() as u64 - 8)
+            .map_err(|_| anyhow!("AVI file is larger than 4 GiB"))?;
+        let frames = self.chunks.len() as u32;
+        let largest = self.chunks.iter().map(|&(_, size)| size).max().unwrap_or(0);
+
+        // Offsets of the fields within the fixed-size headers written by `new`.
+        for (offset, value) in [
+            (4, riff_size),
+            (48, frames),
+            (60, largest),
+            (140, frames),
+            (144, largest),
+            (self.movi - 4, movi_size),
+        ] {
+            self.out.seek(SeekFrom::Start(offset))?;
+            self.out.write_all(&value.to_le_bytes())?;
+        }
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Append a RIFF chunk: its tag, the little-endian size of the body and the
+/// body, padded to an even length.
+fn append_chunk(out: &mut Vec<u8>, tag: &[u8; 4], body: &[u8]) {
+    out.extend(tag);
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Little-endian `u32` at `offset` of `data`.
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn sizes_and_counts() {
+        let mut out = Cursor::new(Vec::new());
+        let mut avi = AviWriter::new(&mut out, 64, 48, (25, 1)).unwrap();
+        // An odd-sized frame is padded to an even length.
+        avi.write_frame(&[1; 5]).unwrap();
+        avi.write_frame(&[2; 8]).unwrap();
+        avi.finish().unwrap();
+        let data = out.into_inner();
+
+        // hdrl: avih at 24, strl at 88 with strh at 100 and strf at 164.
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(&data[12..24], b"LIST\xc0\0\0\0hdrl");
+        assert_eq!(&data[24..32], b"avih\x38\0\0\0");
+        assert_eq!(&data[100..108], b"strh\x38\0\0\0");
+        assert_eq!(&data[164..172], b"strf\x28\0\0\0");
+
+        // Frame counts and the largest frame in avih and strh.
+        assert_eq!(
+            [48, 60, 140, 144].map(|offset| u32_at(&data, offset)),
+            [2, 8, 2, 8]
+        );
+
+        // movi holds the two chunks, of 8 + 6 and 8 + 8 bytes.
+        assert_eq!(&data[212..216], b"LIST");
+        assert_eq!(u32_at(&data, 216), 4 + 14 + 16);
+        assert_eq!(&data[220..232], b"movi00dc\x05\0\0\0");
+        assert_eq!(&data[237..246], b"\x0000dc\x08\0\0\0");
+
+        // idx1 with each chunk's offset from the movi tag and size.
+        assert_eq!(&data[254..262], b"idx1\x20\0\0\0");
+        let entries: Vec<_> = (0..8).map(|i| u32_at(&data, 262 + 4 * i)).collect();
+        let tag = u32::from_le_bytes(*b"00dc");
+        assert_eq!(entries, [tag, 0x10, 4, 5, tag, 0x10, 18, 8]);
+        assert_eq!(data.len(), 294);
+    }
+}
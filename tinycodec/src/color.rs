@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use ndarray::prelude::*;
+
+/// Luma coefficients used to convert between RGB and YUV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Matrix {
+    /// ITU-R BT.601, as used by JPEG/JFIF and standard definition video.
+    Bt601,
+    /// ITU-R BT.709, as used by high definition video.
+    Bt709,
+}
+
+/// Range of code values used by the YUV planes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Range {
+    /// Y, U and V span 0..=255.
+    Full,
+    /// Y spans 16..=235 and U, V span 16..=240.
+    Limited,
+}
+
+impl Matrix {
+    /// The `(Kr, Kb)` luma weights of red and blue.
+    fn coefficients(self) -> (f64, f64) {
+        match self {
+            Matrix::Bt601 => (0.299, 0.114),
+            Matrix::Bt709 => (0.2126, 0.0722),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Matrix::Bt601 => 0,
+            Matrix::Bt709 => 1,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Matrix::Bt601),
+            1 => Ok(Matrix::Bt709),
+            _ => Err(anyhow!("Unsupported colour matrix: {}", value)),
+        }
+    }
+}
+
+impl Range {
+    /// Luma offset, luma excursion and chroma excursion in code values.
+    fn scale(self) -> (f64, f64, f64) {
+        match self {
+            Range::Full => (0.0, 255.0, 255.0),
+            Range::Limited => (16.0, 219.0, 224.0),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Range::Full => 0,
+            Range::Limited => 1,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Range::Full),
+            1 => Ok(Range::Limited),
+            _ => Err(anyhow!("Unsupported colour range: {}", value)),
+        }
+    }
+}
+
+/// Round and saturate a code value to 8 bits.
+fn saturate(x: f64) -> u8 {
+    x.round().clamp(0.0, 255.0) as u8
+}
+
+/// Convert an RGB image to YUV in-place.
+///
+/// This is a destructive conversion, so the input array will be modified.
+/// With `Kr` and `Kb` taken from the `matrix` and `Kg = 1 - Kr - Kb`, the
+/// normalized components are:
+///
+/// Y' = Kr R + Kg G + Kb B
+/// Pb = (B - Y') / (2 - 2 Kb)
+/// Pr = (R - Y') / (2 - 2 Kr)
+///
+/// which are then scaled and offset to code values for the `range`, rounded
+/// and clamped to 0..=255. The resulting YUV values are stored in the input
+/// array, with Y in the first channel, U in the second and V in the third.
+pub fn rgb_to_yuv(mut frame: ArrayViewMut3<u8>, matrix: Matrix, range: Range) {
+    let (kr, kb) = matrix.coefficients();
+    let kg = 1.0 - kr - kb;
+    let (y0, ys, cs) = range.scale();
+
+    for mut pixel in frame.lanes_mut(Axis(2)) {
+        let r = pixel[0] as f64 / 255.0;
+        let g = pixel[1] as f64 / 255.0;
+        let b = pixel[2] as f64 / 255.0;
+        let y = kr * r + kg * g + kb * b;
+        let pb = (b - y) / (2.0 - 2.0 * kb);
+        let pr = (r - y) / (2.0 - 2.0 * kr);
+        pixel[0] = saturate(y0 + ys * y);
+        pixel[1] = saturate(128.0 + cs * pb);
+        pixel[2] = saturate(128.0 + cs * pr);
+    }
+}
+
+/// Convert a YUV image to RGB in-place.
+///
+/// This is a destructive conversion, so the input array will be modified.
+/// It is the exact inverse of [`rgb_to_yuv`] for the same `matrix` and
+/// `range`:
+///
+/// R = Y' + (2 - 2 Kr) Pr
+/// B = Y' + (2 - 2 Kb) Pb
+/// G = (Y' - Kr R - Kb B) / Kg
+///
+/// The results are rounded and clamped to 0..=255 and stored in the input
+/// array, with R in the first channel, G in the second and B in the third.
+pub fn yuv_to_rgb(mut frame: ArrayViewMut3<u8>, matrix: Matrix, range: Range) {
+    let (kr, kb) = matrix.coefficients();
+    let kg = 1.0 - kr - kb;
+    let (y0, ys, cs) = range.scale();
+
+    for mut pixel in frame.lanes_mut(Axis(2)) {
+        let y = (pixel[0] as f64 - y0) / ys;
+        let pb = (pixel[1] as f64 - 128.0) / cs;
+        let pr = (pixel[2] as f64 - 128.0) / cs;
+        let r = y + (2.0 - 2.0 * kr) * pr;
+        let b = y + (2.0 - 2.0 * kb) * pb;
+        let g = (y - kr * r - kb * b) / kg;
+        pixel[0] = saturate(255.0 * r);
+        pixel[1] = saturate(255.0 * g);
+        pixel[2] = saturate(255.0 * b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // Every combination of 18 levels from 0 to 255 per channel.
+        let rgb = Array3::from_shape_fn((18, 18 * 18, 3), |(i, j, c)| {
+            15 * [i, j / 18, j % 18][c] as u8
+        });
+
+        // Limited range codes Y and chroma in coarser steps than RGB, so the
+        // rounding of both can add up to a second code value.
+        for (range, tolerance) in [(Range::Full, 1), (Range::Limited, 2)] {
+            for matrix in [Matrix::Bt601, Matrix::Bt709] {
+                let mut frame = rgb.clone();
+                rgb_to_yuv(frame.view_mut(), matrix, range);
+                yuv_to_rgb(frame.view_mut(), matrix, range);
+                let error = frame.iter().zip(&rgb).map(|(&a, &b)| a.abs_diff(b)).max();
+                assert!(
+                    error <= Some(tolerance),
+                    "{:?} {:?}: {:?}",
+                    matrix,
+                    range,
+                    error
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn limited_range_levels() {
+        let mut frame = array![[[0, 0, 0], [255, 255, 255]]];
+        rgb_to_yuv(frame.view_mut(), Matrix::Bt709, Range::Limited);
+        assert_eq!(frame, array![[[16, 128, 128], [235, 128, 128]]]);
+    }
+}
@@ -0,0 +1,474 @@
+use ndarray::prelude::*;
+use std::f64::consts::{FRAC_1_SQRT_2, SQRT_2};
+
+/// AAN output scale factors, `sqrt(2) cos(k pi / 16)` for `k > 0` and 1 for
+/// the DC term.
+const AAN_SCALE: [f64; 8] = [
+    1.0,
+    1.387039845,
+    1.306562965,
+    1.175875602,
+    1.0,
+    0.785694958,
+    0.541196100,
+    0.275899379,
+];
+
+/// One pass of the Arai-Agui-Nakajima forward DCT over 8 samples `stride`
+/// apart, in-place.
+///
+/// The first stage is the same sum/difference butterfly as Chen's algorithm,
+/// which splits the outputs into even and odd halves. The result is scaled by
+/// `AAN_SCALE` relative to an orthonormal DCT, which the caller folds into
+/// the quantizer.
+fn fdct_1d(data: &mut [f64; 64], offset: usize, stride: usize) {
+    let d = |k: usize| data[offset + k * stride];
+
+    let tmp0 = d(0) + d(7);
+    let tmp7 = d(0) - d(7);
+    let tmp1 = d(1) + d(6);
+    let tmp6 = d(1) - d(6);
+    let tmp2 = d(2) + d(5);
+    let tmp5 = d(2) - d(5);
+    let tmp3 = d(3) + d(4);
+    let tmp4 = d(3) - d(4);
+
+    // Even part
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+
+    let z1 = (tmp12 + tmp13) * FRAC_1_SQRT_2;
+    let even = [tmp10 + tmp11, tmp13 + z1, tmp10 - tmp11, tmp13 - z1];
+
+    // Odd part
+    let tmp10 = tmp4 + tmp5;
+    let tmp11 = tmp5 + tmp6;
+    let tmp12 = tmp6 + tmp7;
+
+    let z5 = (tmp10 - tmp12) * 0.382683433;
+    let z2 = 0.541196100 * tmp10 + z5;
+    let z4 = 1.306562965 * tmp12 + z5;
+    let z3 = tmp11 * FRAC_1_SQRT_2;
+
+    let z11 = tmp7 + z3;
+    let z13 = tmp7 - z3;
+    let odd = [z11 + z4, z13 - z2, z13 + z2, z11 - z4];
+
+    for k in 0..4 {
+        data[offset + 2 * k * stride] = even[k];
+        data[offset + (2 * k + 1) * stride] = odd[k];
+    }
+}
+
+/// One pass of the Arai-Agui-Nakajima inverse DCT over 8 coefficients
+/// `stride` apart, in-place.
+///
+/// The inputs must already be multiplied by `AAN_SCALE`. As in Chen's
+/// algorithm, the even and odd halves are combined by a final butterfly.
+fn idct_1d(data: &mut [f64; 64], offset: usize, stride: usize) {
+    let d = |k: usize| data[offset + k * stride];
+
+    // Even part
+    let tmp10 = d(0) + d(4);
+    let tmp11 = d(0) - d(4);
+    let tmp13 = d(2) + d(6);
+    let tmp12 = (d(2) - d(6)) * SQRT_2 - tmp13;
+
+    let tmp0 = tmp10 + tmp13;
+    let tmp3 = tmp10 - tmp13;
+    let tmp1 = tmp11 + tmp12;
+    let tmp2 = tmp11 - tmp12;
+
+    // Odd part
+    let z13 = d(5) + d(3);
+    let z10 = d(5) - d(3);
+    let z11 = d(1) + d(7);
+    let z12 = d(1) - d(7);
+
+    let tmp7 = z11 + z13;
+    let tmp11 = (z11 - z13) * SQRT_2;
+
+    let z5 = (z10 + z12) * 1.847759065;
+    let tmp10 = 1.082392200 * z12 - z5;
+    let tmp12 = -2.613125930 * z10 + z5;
+
+    let tmp6 = tmp12 - tmp7;
+    let tmp5 = tmp11 - tmp6;
+    let tmp4 = tmp10 + tmp5;
+
+    let out = [
+        tmp0 + tmp7,
+        tmp1 + tmp6,
+        tmp2 + tmp5,
+        tmp3 - tmp4,
+        tmp3 + tmp4,
+        tmp2 - tmp5,
+        tmp1 - tmp6,
+        tmp0 - tmp7,
+    ];
+    for (k, v) in out.into_iter().enumerate() {
+        data[offset + k * stride] = v;
+    }
+}
+
+/// Forward DCT of 8x8 blocks in natural order, after subtracting `shift`
+/// from every sample, divided by the quantizer steps but not yet rounded.
+///
+/// The AAN output scaling and the 1/8 normalisation of the 2D transform are
+/// folded into the quantizer divisors, so each coefficient costs a single
+/// multiply on top of the butterflies.
+fn quotients(blocks: ArrayView2<i64>, table: &[i64; 64], shift: i64) -> Array2<f64> {
+    let scale: [f64; 64] = std::array::from_fn(|i| {
+        1.0 / (table[i] as f64 * AAN_SCALE[i / 8] * AAN_SCALE[i % 8] * 8.0)
+    });
+    let mut data = [0.0; 64];
+    let mut result = Array2::zeros(blocks.raw_dim());
+
+    for (block, mut out) in blocks.rows().into_iter().zip(result.rows_mut()) {
+        for (d, &v) in data.iter_mut().zip(block.iter()) {
+            *d = (v - shift) as f64;
+        }
+        for row in 0..8 {
+            fdct_1d(&mut data, row * 8, 1);
+        }
+        for col in 0..8 {
+            fdct_1d(&mut data, col, 8);
+        }
+        for ((q, &d), &s) in out.iter_mut().zip(data.iter()).zip(scale.iter()) {
+            *q = d * s;
+        }
+    }
+
+    result
+}
+
+/// Forward DCT and quantization of 8x8 blocks in natural order, rounding
+/// the [`quotients`] to the nearest integer.
+fn forward(mut blocks: ArrayViewMut2<i64>, table: &[i64; 64], shift: i64) {
+    let quotients = quotients(blocks.view(), table, shift);
+    blocks.zip_mut_with(&quotients, |v, &q| *v = q.round() as i64);
+}
+
+/// Dequantization and inverse DCT of 8x8 blocks in natural order, passing
+/// every rounded sample through `finish`.
+///
+/// The quantizer steps, the AAN input scaling and the 1/8 normalisation are
+/// folded into one multiplier per coefficient.
+fn inverse<F>(mut blocks: ArrayViewMut2<i64>, table: &[i64; 64], finish: F)
+where
+    F: Fn(i64) -> i64,
+{
+    let scale: [f64; 64] =
+        std::array::from_fn(|i| table[i] as f64 * AAN_SCALE[i / 8] * AAN_SCALE[i % 8] / 8.0);
+    let mut data = [0.0; 64];
+
+    for mut block in blocks.rows_mut() {
+        for ((d, &v), &s) in data.iter_mut().zip(block.iter()).zip(scale.iter()) {
+            *d = v as f64 * s;
+        }
+        for col in 0..8 {
+            idct_1d(&mut data, col, 8);
+        }
+        for row in 0..8 {
+            idct_1d(&mut data, row * 8, 1);
+        }
+        for (v, &d) in block.iter_mut().zip(data.iter()) {
+            *v = finish(d.round() as i64);
+        }
+    }
+}
+
+/// Forward DCT and quantization of level-shifted 8x8 sample blocks.
+pub fn fdct(blocks: ArrayViewMut2<i64>, table: &[i64; 64]) {
+    forward(blocks, table, 128);
+}
+
+/// The unrounded coefficients that [`fdct`] would produce.
+pub fn fdct_quotients(blocks: ArrayView2<i64>, table: &[i64; 64]) -> Array2<f64> {
+    quotients(blocks, table, 128)
+}
+
+/// Inverse of [`fdct`]. Samples are clamped to -128..=127 and shifted back
+/// to 0..=255.
+pub fn idct(blocks: ArrayViewMut2<i64>, table: &[i64; 64]) {
+    inverse(blocks, table, |v| v.clamp(-128, 127) + 128);
+}
+
+/// Forward DCT and quantization of 8x8 blocks of prediction residuals, which
+/// are already centred on zero.
+pub fn fdct_residual(blocks: ArrayViewMut2<i64>, table: &[i64; 64]) {
+    forward(blocks, table, 0);
+}
+
+/// The unrounded coefficients that [`fdct_residual`] would produce.
+pub fn fdct_residual_quotients(blocks: ArrayView2<i64>, table: &[i64; 64]) -> Array2<f64> {
+    quotients(blocks, table, 0)
+}
+
+/// Inverse of [`fdct_residual`]. Residuals are left unclamped, the caller
+/// clamps after adding them to the prediction.
+pub fn idct_residual(blocks: ArrayViewMut2<i64>, table: &[i64; 64]) {
+    inverse(blocks, table, |v| v);
+}
+
+/// Reference forward DCT by dense matrix multiplication, kept for `bench`.
+pub fn matrix_fdct(mut blocks: ArrayViewMut2<i64>, table: &[i64; 64]) {
+    let (num_blocks, _) = blocks.dim();
+    let t = array![
+        [
+            0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339,
+            0.35355339,
+        ],
+        [
+            0.49039264,
+            0.41573481,
+            0.27778512,
+            0.09754516,
+            -0.09754516,
+            -0.27778512,
+            -0.41573481,
+            -0.49039264,
+        ],
+        [
+            0.46193977,
+            0.19134172,
+            -0.19134172,
+            -0.46193977,
+            -0.46193977,
+            -0.19134172,
+            0.19134172,
+            0.46193977,
+        ],
+        [
+            0.41573481,
+            -0.09754516,
+            -0.49039264,
+            -0.27778512,
+            0.27778512,
+            0.49039264,
+            0.09754516,
+            -0.41573481,
+        ],
+        [
+            0.35355339,
+            -0.35355339,
+            -0.35355339,
+            0.35355339,
+            0.35355339,
+            -0.35355339,
+            -0.35355339,
+            0.35355339,
+        ],
+        [
+            0.27778512,
+            -0.49039264,
+            0.09754516,
+            0.41573481,
+            -0.41573481,
+            -0.09754516,
+            0.49039264,
+            -0.27778512,
+        ],
+        [
+            0.19134172,
+            -0.46193977,
+            0.46193977,
+            -0.19134172,
+            -0.19134172,
+            0.46193977,
+            -0.46193977,
+            0.19134172,
+        ],
+        [
+            0.09754516,
+            -0.27778512,
+            0.41573481,
+            -0.49039264,
+            0.49039264,
+            -0.41573481,
+            0.27778512,
+            -0.09754516,
+        ],
+    ];
+    let mut block = Array2::<f64>::zeros((8, 8));
+    let tt = t.t();
+
+    blocks -= 128;
+
+    for n in 0..num_blocks {
+        block.indexed_iter_mut().for_each(|((i, j), v)| {
+            *v = blocks[(n, i * 8 + j)] as f64;
+        });
+        let x = t.dot(&block.dot(&tt));
+        blocks
+            .slice_mut(s![n, ..])
+            .indexed_iter_mut()
+            .for_each(|(i, v)| {
+                *v = (x[[i / 8, i % 8]] / table[i] as f64).round() as i64;
+            })
+    }
+}
+
+/// Reference inverse DCT by dense matrix multiplication, kept for `bench`.
+pub fn matrix_idct(mut blocks: ArrayViewMut2<i64>, table: &[i64; 64]) {
+    let (num_blocks, _) = blocks.dim();
+    let mut block = Array2::<f64>::zeros((8, 8));
+    let t = array![
+        [
+            0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339,
+            0.35355339,
+        ],
+        [
+            0.49039264,
+            0.41573481,
+            0.27778512,
+            0.09754516,
+            -0.09754516,
+            -0.27778512,
+            -0.41573481,
+            -0.49039264,
+        ],
+        [
+            0.46193977,
+            0.19134172,
+            -0.19134172,
+            -0.46193977,
+            -0.46193977,
+            -0.19134172,
+            0.19134172,
+            0.46193977,
+        ],
+        [
+            0.41573481,
+            -0.09754516,
+            -0.49039264,
+            -0.27778512,
+            0.27778512,
+            0.49039264,
+            0.09754516,
+            -0.41573481,
+        ],
+        [
+            0.35355339,
+            -0.35355339,
+            -0.35355339,
+            0.35355339,
+            0.35355339,
+            -0.35355339,
+            -0.35355339,
+            0.35355339,
+        ],
+        [
+            0.27778512,
+            -0.49039264,
+            0.09754516,
+            0.41573481,
+            -0.41573481,
+            -0.09754516,
+            0.49039264,
+            -0.27778512,
+        ],
+        [
+            0.19134172,
+            -0.46193977,
+            0.46193977,
+            -0.19134172,
+            -0.19134172,
+            0.46193977,
+            -0.46193977,
+            0.19134172,
+        ],
+        [
+            0.09754516,
+            -0.27778512,
+            0.41573481,
+            -0.49039264,
+            0.49039264,
+            -0.41573481,
+            0.27778512,
+            -0.09754516,
+        ],
+    ];
+    let tt = t.t();
+
+    for n in 0..num_blocks {
+        block.indexed_iter_mut().for_each(|((i, j), v)| {
+            *v = (blocks[(n, i * 8 + j)] * table[i * 8 + j]) as f64;
+        });
+        let x = tt.dot(&block.dot(&t));
+        blocks
+            .slice_mut(s![n, ..])
+            .indexed_iter_mut()
+            .for_each(|(i, v)| {
+                *v = (x[[i / 8, i % 8]].round() as i64).clamp(-128, 127);
+            })
+    }
+
+    blocks += 128;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks of pseudo-random samples in 0..=255.
+    fn random_blocks(count: usize) -> Array2<i64> {
+        let mut seed = 1u32;
+        Array2::from_shape_fn((count, 64), |_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as i64 % 256
+        })
+    }
+
+    /// Largest difference between two sets of blocks.
+    fn max_error(a: &Array2<i64>, b: &Array2<i64>) -> i64 {
+        a.iter().zip(b).map(|(a, b)| (a - b).abs()).max().unwrap()
+    }
+
+    #[test]
+    fn aan_matches_matrix() {
+        let tables = [[1; 64], crate::LUMA_QUANTIZATION_TABLE];
+        for table in &tables {
+            let samples = random_blocks(64);
+
+            let mut aan = samples.clone();
+            let mut matrix = samples.clone();
+            fdct(aan.view_mut(), table);
+            matrix_fdct(matrix.view_mut(), table);
+            assert!(max_error(&aan, &matrix) <= 1);
+
+            let coefficients = matrix;
+            let mut aan = coefficients.clone();
+            let mut matrix = coefficients;
+            idct(aan.view_mut(), table);
+            matrix_idct(matrix.view_mut(), table);
+            assert!(max_error(&aan, &matrix) <= 1);
+        }
+    }
+
+    #[test]
+    fn aan_matches_matrix_on_residuals() {
+        // The matrix transforms work on level-shifted samples, so residuals
+        // are shifted into them and back out, clamped as `idct` clamps.
+        let tables = [[1; 64], crate::LUMA_QUANTIZATION_TABLE];
+        for table in &tables {
+            let residuals = random_blocks(64) * 2 - 255;
+
+            let mut aan = residuals.clone();
+            let mut matrix = residuals + 128;
+            fdct_residual(aan.view_mut(), table);
+            matrix_fdct(matrix.view_mut(), table);
+            assert!(max_error(&aan, &matrix) <= 1);
+
+            let coefficients = matrix;
+            let mut aan = coefficients.clone();
+            let mut matrix = coefficients;
+            idct_residual(aan.view_mut(), table);
+            matrix_idct(matrix.view_mut(), table);
+            aan.mapv_inplace(|v| v.clamp(-128, 127) + 128);
+            assert!(max_error(&aan, &matrix) <= 1);
+        }
+    }
+}
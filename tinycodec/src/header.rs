@@ -0,0 +1,727 @@
+use crate::color::{Matrix, Range};
+use anyhow::{anyhow, Result};
+use bitstream::{BigEndian, BitRead, BitReader, BitWrite, BitWriter};
+use clap::ValueEnum;
+use core::str;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Magic bytes at the start of every tinycodec stream.
+pub const MAGIC: &[u8; 4] = b"tiny";
+
+/// Magic bytes at the start of the frame index table.
+pub const INDEX_MAGIC: &[u8; 4] = b"tidx";
+
+/// Version of the container layout written by this build of `encode`.
+pub const VERSION: u8 = 9;
+
+/// Oldest container version `decode` still reads, the first with separate
+/// luma and chroma tables.
+pub const MIN_VERSION: u8 = 4;
+
+/// Flag set when the header carries per-stream Huffman tables.
+pub const FLAG_HUFFMAN_TABLES: u16 = 1 << 0;
+
+/// Flag set when DC coefficients are coded as differences from the previous
+/// block rather than absolutely.
+pub const FLAG_DC_PREDICTION: u16 = 1 << 1;
+
+/// Flag set when every plane is split into independently coded slices.
+pub const FLAG_RESTART_INTERVAL: u16 = 1 << 2;
+
+/// Flag set when P-frames copy unchanged blocks from the previous frame
+/// instead of using motion compensation.
+pub const FLAG_SKIP_BLOCKS: u16 = 1 << 3;
+
+/// Flag set when every frame carries its own quality factor.
+pub const FLAG_FRAME_QUALITY: u16 = 1 << 4;
+
+/// Mask of the flag bits understood by this build of `decode`.
+pub const KNOWN_FLAGS: u16 = FLAG_HUFFMAN_TABLES
+    | FLAG_DC_PREDICTION
+    | FLAG_RESTART_INTERVAL
+    | FLAG_SKIP_BLOCKS
+    | FLAG_FRAME_QUALITY;
+
+/// Mask of the flag bits defined in container `version`.
+fn known_flags(version: u8) -> u16 {
+    match version {
+        ..=4 => 0,
+        5 => FLAG_HUFFMAN_TABLES,
+        6..=7 => FLAG_HUFFMAN_TABLES | FLAG_DC_PREDICTION,
+        8 => FLAG_HUFFMAN_TABLES | FLAG_DC_PREDICTION | FLAG_RESTART_INTERVAL,
+        _ => KNOWN_FLAGS,
+    }
+}
+
+/// Layout of the chroma planes relative to the luma plane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Chroma {
+    /// U and V at full resolution.
+    #[value(name = "444")]
+    Yuv444,
+    /// U and V are decimated by two horizontally.
+    #[value(name = "422")]
+    Yuv422,
+    /// U and V are decimated by two in both directions.
+    #[value(name = "420")]
+    Yuv420,
+    /// Luma only, the decoder fills U and V with mid-grey.
+    #[value(name = "400")]
+    Gray,
+}
+
+impl Chroma {
+    /// Vertical and horizontal decimation factors of the chroma planes.
+    pub fn subsampling(self) -> (usize, usize) {
+        match self {
+            Chroma::Yuv444 | Chroma::Gray => (1, 1),
+            Chroma::Yuv422 => (1, 2),
+            Chroma::Yuv420 => (2, 2),
+        }
+    }
+
+    /// Height and width in pixels of a minimum coded unit, the smallest area
+    /// that covers a whole number of blocks in every plane.
+    pub fn mcu_size(self) -> (usize, usize) {
+        let (sy, sx) = self.subsampling();
+        (8 * sy, 8 * sx)
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Chroma::Yuv420 => 0,
+            Chroma::Yuv422 => 1,
+            Chroma::Yuv444 => 2,
+            Chroma::Gray => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Chroma::Yuv420),
+            1 => Ok(Chroma::Yuv422),
+            2 => Ok(Chroma::Yuv444),
+            3 => Ok(Chroma::Gray),
+            _ => Err(anyhow!("Unsupported chroma layout: {}", value)),
+        }
+    }
+}
+
+/// Huffman table in the form of a JPEG DHT segment.
+///
+/// `bits[i]` is the number of codes of length `i + 1` and `values` lists the
+/// symbols in order of increasing code length. DC symbols are coefficient
+/// sizes and AC symbols are `(run << 4) | size`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanSpec {
+    pub bits: [u8; 16],
+    pub values: Vec<u8>,
+}
+
+impl HuffmanSpec {
+    fn write<W>(&self, writer: &mut BitWriter<W, BigEndian>) -> Result<()>
+    where
+        W: Write,
+    {
+        writer.write_bytes(&self.bits)?;
+        writer.write_bytes(&self.values)?;
+
+        Ok(())
+    }
+
+    fn read<R>(reader: &mut BitReader<R, BigEndian>) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut bits = [0u8; 16];
+        reader.read_bytes(&mut bits)?;
+
+        let count = bits.iter().map(|&n| n as usize).sum();
+        if count > 256 {
+            return Err(anyhow!("Invalid Huffman table: {} symbols", count));
+        }
+
+        let mut values = vec![0u8; count];
+        reader.read_bytes(&mut values)?;
+
+        Ok(HuffmanSpec { bits, values })
+    }
+}
+
+/// Stream header written once at the start of the file.
+///
+/// The on-disk layout is big-endian:
+///
+/// | Field            | Bits |
+/// |------------------|------|
+/// | magic (`tiny`)   | 32   |
+/// | version          | 8    |
+/// | width            | 16   |
+/// | height           | 16   |
+/// | frame rate num   | 32   |
+/// | frame rate den   | 32   |
+/// | frame count      | 32   |
+/// | GOP size         | 16   |
+/// | chroma layout    | 8    |
+/// | colour matrix    | 8    |
+/// | colour range     | 8    |
+/// | flags            | 16   |
+/// | index offset     | 64   |
+/// | luma table       | 64×8 |
+/// | chroma table     | 64×8 |
+///
+/// The quantization tables are stored in natural (row-major) order. The luma
+/// table applies to the Y plane and the chroma table to the U and V planes.
+///
+/// If [`FLAG_HUFFMAN_TABLES`] is set the quantization tables are followed by
+/// the DC and then the AC [`HuffmanSpec`], each as 16 code length counts and
+/// the symbol list. Otherwise the decoder uses the built-in tables.
+///
+/// If [`FLAG_RESTART_INTERVAL`] is set the header ends with a 16-bit restart
+/// interval in MCU rows, and each frame is coded as slices (see
+/// [`join_slices`]).
+///
+/// Every `GOP size`th frame, starting with the first, is an I-frame coded on
+/// its own. The frames in between are P-frames, predicted from the previous
+/// reconstructed frame by one motion vector per 16x16 macroblock and coded
+/// as the residual. A GOP size of 1 makes every frame an I-frame.
+///
+/// If [`FLAG_SKIP_BLOCKS`] is set, P-frames use conditional replenishment
+/// instead: every block starts with a 1-bit flag, set for blocks copied
+/// unchanged from the previous frame and clear for blocks coded as in an
+/// I-frame. DC prediction then runs over the coded blocks only.
+///
+/// If [`FLAG_FRAME_QUALITY`] is set, every frame starts with a byte holding a
+/// quality factor from 1 to 100. It scales both quantization tables for that
+/// frame the way `encode --quality` scales the base tables, so 50 leaves them
+/// unchanged.
+///
+/// Streams from [`MIN_VERSION`] on are still read. Version 8 and older have
+/// no GOP size, as every frame is an I-frame, and version 6 and older have no
+/// colour matrix and range, as they are coded in full range BT.601. Flags
+/// added after a stream's version are never set in it.
+///
+/// The header is followed by `frame count` byte-aligned frames, each prefixed
+/// with its length in bytes as a 32-bit integer, and then by the frame index
+/// (see [`write_index`]) at `index offset`. A frame may end with zero bytes
+/// of padding, which the decoder ignores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub width: usize,
+    pub height: usize,
+    pub frame_rate: (u32, u32),
+    pub frame_count: usize,
+    pub gop: usize,
+    pub chroma: Chroma,
+    pub matrix: Matrix,
+    pub range: Range,
+    pub index_offset: u64,
+    pub luma_table: [i64; 64],
+    pub chroma_table: [i64; 64],
+    pub huffman: Option<(HuffmanSpec, HuffmanSpec)>,
+    pub dc_prediction: bool,
+    pub restart_interval: Option<u16>,
+    pub skip_blocks: bool,
+    pub frame_quality: bool,
+}
+
+impl Header {
+    /// Frame size rounded up to a whole number of MCUs. The encoder pads each
+    /// frame to this size and the decoder crops back to `height` x `width`.
+    pub fn padded_size(&self) -> (usize, usize) {
+        let (mcu_height, mcu_width) = self.chroma.mcu_size();
+        (
+            self.height.div_ceil(mcu_height) * mcu_height,
+            self.width.div_ceil(mcu_width) * mcu_width,
+        )
+    }
+
+    /// Size of the padded U and V planes, empty for luma-only streams.
+    pub fn chroma_size(&self) -> (usize, usize) {
+        let (height, width) = self.padded_size();
+        let (sy, sx) = self.chroma.subsampling();
+        match self.chroma {
+            Chroma::Gray => (0, 0),
+            _ => (height / sy, width / sx),
+        }
+    }
+
+    /// Whether frame `index` is an I-frame rather than a P-frame.
+    pub fn is_keyframe(&self, index: usize) -> bool {
+        index.is_multiple_of(self.gop)
+    }
+
+    /// Number of blocks in each slice of the luma and chroma planes.
+    ///
+    /// A slice covers `restart_interval` MCU rows, which is that many block
+    /// rows of a chroma plane and `sy` times as many of the luma plane. The
+    /// last slice of a plane may be shorter. Without a restart interval each
+    /// plane is a single slice.
+    pub fn slice_blocks(&self) -> (usize, usize) {
+        let (height, width) = self.padded_size();
+        let (ch, cw) = self.chroma_size();
+        match self.restart_interval {
+            Some(rows) => {
+                let (sy, _) = self.chroma.subsampling();
+                let rows = rows as usize;
+                (rows * sy * (width / 8), rows * (cw / 8))
+            }
+            None => ((height / 8) * (width / 8), (ch / 8) * (cw / 8)),
+        }
+    }
+
+    /// Flag bits describing the optional parts of this header.
+    pub fn flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.huffman.is_some() {
+            flags |= FLAG_HUFFMAN_TABLES;
+        }
+        if self.dc_prediction {
+            flags |= FLAG_DC_PREDICTION;
+        }
+        if self.restart_interval.is_some() {
+            flags |= FLAG_RESTART_INTERVAL;
+        }
+        if self.skip_blocks {
+            flags |= FLAG_SKIP_BLOCKS;
+        }
+        if self.frame_quality {
+            flags |= FLAG_FRAME_QUALITY;
+        }
+        flags
+    }
+
+    pub fn write<W>(&self, writer: &mut BitWriter<W, BigEndian>) -> Result<()>
+    where
+        W: Write,
+    {
+        writer.write_bytes(MAGIC)?;
+        writer.write_out::<8, _>(VERSION)?;
+        writer.write_out::<16, _>(u16::try_from(self.width)?)?;
+        writer.write_out::<16, _>(u16::try_from(self.height)?)?;
+        writer.write_out::<32, _>(self.frame_rate.0)?;
+        writer.write_out::<32, _>(self.frame_rate.1)?;
+        writer.write_out::<32, _>(u32::try_from(self.frame_count)?)?;
+        writer.write_out::<16, _>(u16::try_from(self.gop)?)?;
+        writer.write_out::<8, _>(self.chroma.to_u8())?;
+        writer.write_out::<8, _>(self.matrix.to_u8())?;
+        writer.write_out::<8, _>(self.range.to_u8())?;
+        writer.write_out::<16, _>(self.flags())?;
+        writer.write_out::<64, _>(self.index_offset)?;
+        for &q in self.luma_table.iter().chain(&self.chroma_table) {
+            writer.write_out::<8, _>(u8::try_from(q)?)?;
+        }
+        if let Some((dc, ac)) = &self.huffman {
+            dc.write(writer)?;
+            ac.write(writer)?;
+        }
+        if let Some(rows) = self.restart_interval {
+            writer.write_out::<16, _>(rows)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read<R>(reader: &mut BitReader<R, BigEndian>) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut magic = [0u8; 4];
+        reader.read_bytes(&mut magic)?;
+
+        if !magic.eq(MAGIC) {
+            return Err(anyhow!(
+                "Invalid header: {:?}",
+                str::from_utf8(&magic).unwrap_or("<binary>")
+            ));
+        }
+
+        let version = reader.read_in::<8, u8>()?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(anyhow!(
+                "Unsupported stream version {} (this build decodes versions {} to {})",
+                version,
+                MIN_VERSION,
+                VERSION
+            ));
+        }
+
+        let width = reader.read_in::<16, u16>()? as usize;
+        let height = reader.read_in::<16, u16>()? as usize;
+        let frame_rate = (reader.read_in::<32, u32>()?, reader.read_in::<32, u32>()?);
+        let frame_count = reader.read_in::<32, u32>()? as usize;
+        let gop = match version {
+            9.. => reader.read_in::<16, u16>()? as usize,
+            _ => 1,
+        };
+        let chroma = Chroma::from_u8(reader.read_in::<8, u8>()?)?;
+        let (matrix, range) = match version {
+            7.. => (
+                Matrix::from_u8(reader.read_in::<8, u8>()?)?,
+                Range::from_u8(reader.read_in::<8, u8>()?)?,
+            ),
+            _ => (Matrix::Bt601, Range::Full),
+        };
+        let flags = reader.read_in::<16, u16>()?;
+        let index_offset = reader.read_in::<64, u64>()?;
+        let mut luma_table = [0i64; 64];
+        let mut chroma_table = [0i64; 64];
+        for q in luma_table.iter_mut().chain(chroma_table.iter_mut()) {
+            *q = reader.read_in::<8, u8>()? as i64;
+        }
+
+        if width == 0 || height == 0 {
+            return Err(anyhow!("Invalid frame size: {}x{}", width, height));
+        }
+
+        if frame_rate.0 == 0 || frame_rate.1 == 0 {
+            return Err(anyhow!(
+                "Invalid frame rate: {}/{}",
+                frame_rate.0,
+                frame_rate.1
+            ));
+        }
+
+        if gop == 0 {
+            return Err(anyhow!("Invalid GOP size: 0"));
+        }
+
+        if luma_table.contains(&0) || chroma_table.contains(&0) {
+            return Err(anyhow!("Invalid quantization table: contains a zero step"));
+        }
+
+        if flags & !known_flags(version) != 0 {
+            return Err(anyhow!("Unsupported stream flags: {:#06x}", flags));
+        }
+
+        let huffman = if flags & FLAG_HUFFMAN_TABLES != 0 {
+            Some((HuffmanSpec::read(reader)?, HuffmanSpec::read(reader)?))
+        } else {
+            None
+        };
+
+        let restart_interval = if flags & FLAG_RESTART_INTERVAL != 0 {
+            match reader.read_in::<16, u16>()? {
+                0 => return Err(anyhow!("Invalid restart interval: 0")),
+                rows => Some(rows),
+            }
+        } else {
+            None
+        };
+
+        Ok(Header {
+            width,
+            height,
+            frame_rate,
+            frame_count,
+            gop,
+            chroma,
+            matrix,
+            range,
+            index_offset,
+            luma_table,
+            chroma_table,
+            huffman,
+            dc_prediction: flags & FLAG_DC_PREDICTION != 0,
+            restart_interval,
+            skip_blocks: flags & FLAG_SKIP_BLOCKS != 0,
+            frame_quality: flags & FLAG_FRAME_QUALITY != 0,
+        })
+    }
+}
+
+/// Write one encoded frame, prefixed with its length in bytes.
+pub fn write_frame<W>(writer: &mut BitWriter<W, BigEndian>, data: &[u8]) -> Result<()>
+where
+    W: Write,
+{
+    writer.write_out::<32, _>(u32::try_from(data.len())?)?;
+    writer.write_bytes(data)?;
+
+    Ok(())
+}
+
+/// Read the next length-prefixed frame into a byte buffer.
+pub fn read_frame<R>(reader: &mut BitReader<R, BigEndian>) -> Result<Vec<u8>>
+where
+    R: Read,
+{
+    let len = reader.read_in::<32, u32>()? as usize;
+    let mut data = vec![0u8; len];
+    reader.read_bytes(&mut data)?;
+
+    Ok(data)
+}
+
+/// Concatenate separately coded slices into the payload of one frame.
+///
+/// The payload starts with a table of 32-bit offsets, one per slice, giving
+/// the position of each slice relative to the end of the table. The slices
+/// follow in plane order (Y, U, V) and within a plane from top to bottom,
+/// after a slice holding the motion vectors in P-frames. Each slice starts
+/// byte aligned with a fresh DC predictor, so slices can be decoded
+/// independently of each other.
+pub fn join_slices(slices: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let size: usize = slices.iter().map(Vec::len).sum();
+    let mut data = Vec::with_capacity(4 * slices.len() + size);
+    let mut offset = 0;
+    for slice in slices {
+        data.extend_from_slice(&u32::try_from(offset)?.to_be_bytes());
+        offset += slice.len();
+    }
+    for slice in slices {
+        data.extend_from_slice(slice);
+    }
+
+    Ok(data)
+}
+
+/// Split a frame payload written by [`join_slices`] into `count` slices.
+pub fn split_slices(data: &[u8], count: usize) -> Result<Vec<&[u8]>> {
+    if data.len() < 4 * count {
+        return Err(anyhow!("Truncated slice table"));
+    }
+
+    let (table, body) = data.split_at(4 * count);
+    let mut offsets: Vec<usize> = table
+        .chunks_exact(4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .collect();
+    offsets.push(body.len());
+
+    offsets
+        .windows(2)
+        .map(|w| {
+            body.get(w[0]..w[1])
+                .ok_or_else(|| anyhow!("Invalid slice offset: {}", w[0]))
+        })
+        .collect()
+}
+
+/// Write the frame index table.
+///
+/// The table is the `tidx` magic, the number of entries as a 32-bit integer
+/// and then one 64-bit entry per frame, in frame order, holding the absolute
+/// byte offset of that frame's length prefix.
+pub fn write_index<W>(writer: &mut BitWriter<W, BigEndian>, offsets: &[u64]) -> Result<()>
+where
+    W: Write,
+{
+    writer.write_bytes(INDEX_MAGIC)?;
+    writer.write_out::<32, _>(u32::try_from(offsets.len())?)?;
+    for &offset in offsets {
+        writer.write_out::<64, _>(offset)?;
+    }
+
+    Ok(())
+}
+
+/// Read the frame index table referenced by `header`.
+///
+/// The reader is left positioned just after the table.
+pub fn read_index<R>(reader: &mut BitReader<R, BigEndian>, header: &Header) -> Result<Vec<u64>>
+where
+    R: Read + Seek,
+{
+    if header.index_offset == 0 {
+        return Err(anyhow!("Stream has no frame index"));
+    }
+
+    reader.seek_bits(SeekFrom::Start(header.index_offset * 8))?;
+
+    let mut magic = [0u8; 4];
+    reader.read_bytes(&mut magic)?;
+    if !magic.eq(INDEX_MAGIC) {
+        return Err(anyhow!(
+            "Invalid frame index at offset {}",
+            header.index_offset
+        ));
+    }
+
+    let entries = reader.read_in::<32, u32>()? as usize;
+    if entries != header.frame_count {
+        return Err(anyhow!(
+            "Frame index has {} entries but the header declares {} frames",
+            entries,
+            header.frame_count
+        ));
+    }
+
+    (0..entries)
+        .map(|_| Ok(reader.read_in::<64, u64>()?))
+        .collect()
+}
+
+/// Approximate a floating point frame rate as a rational number.
+///
+/// NTSC-style rates such as 29.97 are mapped onto their exact `n/1001` form,
+/// everything else is expressed in thousandths of a frame per second.
+pub fn frame_rate_to_rational(frame_rate: f32) -> (u32, u32) {
+    let ntsc = (frame_rate as f64 * 1001.0).round() as u32;
+    if ntsc.is_multiple_of(1000) && ntsc > 0 {
+        return (ntsc, 1001);
+    }
+
+    let num = (frame_rate as f64 * 1000.0).round() as u32;
+    let divisor = gcd(num, 1000);
+    (num / divisor, 1000 / divisor)
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Header with every optional part present.
+    fn header() -> Header {
+        Header {
+            width: 37,
+            height: 23,
+            frame_rate: (30000, 1001),
+            frame_count: 12,
+            gop: 4,
+            chroma: Chroma::Yuv422,
+            matrix: Matrix::Bt709,
+            range: Range::Limited,
+            index_offset: 4096,
+            luma_table: std::array::from_fn(|i| 1 + i as i64),
+            chroma_table: std::array::from_fn(|i| 255 - i as i64),
+            huffman: Some((
+                HuffmanSpec {
+                    bits: [0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    values: vec![0, 1, 2],
+                },
+                HuffmanSpec {
+                    bits: [0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    values: vec![0x00, 0x01],
+                },
+            )),
+            dc_prediction: true,
+            restart_interval: Some(3),
+            skip_blocks: true,
+            frame_quality: true,
+        }
+    }
+
+    fn write(header: &Header) -> Vec<u8> {
+        let mut writer = BitWriter::endian(Vec::new(), BigEndian);
+        header.write(&mut writer).unwrap();
+        writer.into_writer()
+    }
+
+    /// Error message of reading a header from `data`.
+    fn read_err(data: Vec<u8>) -> String {
+        let mut reader = BitReader::endian(Cursor::new(data), BigEndian);
+        Header::read(&mut reader).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        for header in [
+            header(),
+            Header {
+                huffman: None,
+                dc_prediction: false,
+                restart_interval: None,
+                skip_blocks: false,
+                frame_quality: false,
+                ..header()
+            },
+        ] {
+            let data = write(&header);
+            let mut reader = BitReader::endian(Cursor::new(data), BigEndian);
+            assert_eq!(Header::read(&mut reader).unwrap(), header);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        let mut data = write(&header());
+        data[4] = VERSION + 1;
+        assert!(read_err(data.clone()).contains("version"));
+        data[4] = MIN_VERSION - 1;
+        assert!(read_err(data).contains("version"));
+    }
+
+    #[test]
+    fn reads_older_versions() {
+        // Version 8 has no GOP size, which sits at bytes 21 and 22, and
+        // version 6 no colour matrix and range either, at bytes 24 and 25.
+        let header = Header {
+            gop: 1,
+            matrix: Matrix::Bt601,
+            range: Range::Full,
+            restart_interval: None,
+            skip_blocks: false,
+            frame_quality: false,
+            ..header()
+        };
+        let mut data = write(&Header {
+            gop: 4,
+            matrix: Matrix::Bt709,
+            range: Range::Limited,
+            ..header.clone()
+        });
+        data.drain(24..26);
+        data.drain(21..23);
+        data[4] = 6;
+
+        let mut reader = BitReader::endian(Cursor::new(data), BigEndian);
+        assert_eq!(Header::read(&mut reader).unwrap(), header);
+    }
+
+    #[test]
+    fn rejects_flags_newer_than_the_version() {
+        // A version 8 header with skip blocks set.
+        let mut data = write(&Header {
+            restart_interval: None,
+            frame_quality: false,
+            ..header()
+        });
+        data.drain(21..23);
+        data[4] = 8;
+        assert!(read_err(data).contains("flags"));
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let mut data = write(&header());
+        data[26] |= 0x80;
+        assert!(read_err(data).contains("flags"));
+    }
+
+    #[test]
+    fn rejects_empty_frames() {
+        assert!(read_err(write(&Header {
+            width: 0,
+            ..header()
+        }))
+        .contains("frame size"));
+        assert!(read_err(write(&Header {
+            height: 0,
+            ..header()
+        }))
+        .contains("frame size"));
+    }
+
+    #[test]
+    fn rejects_gop_0() {
+        assert!(read_err(write(&Header { gop: 0, ..header() })).contains("GOP"));
+    }
+
+    #[test]
+    fn rejects_restart_interval_0() {
+        let header = Header {
+            restart_interval: Some(0),
+            ..header()
+        };
+        assert!(read_err(write(&header)).contains("restart interval"));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::LUMA_QUANTIZATION_TABLE;
+use ndarray::prelude::*;
+
+/// Width of the coefficient and 1D IDCT registers (`WIDTH` in `idct_1d.sv`).
+const WIDTH: u32 = 12;
+
+// cos/sin multiples of pi/16, scaled by 2^14 as in `idct_1d.sv`.
+const A: i64 = 11585;
+const B: i64 = 15136;
+const C: i64 = 6269;
+const D: i64 = 16069;
+const E: i64 = 13622;
+const F: i64 = 9102;
+const G: i64 = 3196;
+
+/// Truncate `x` to a `bits`-wide two's complement value.
+fn wrap(x: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (x << shift) >> shift
+}
+
+/// One pass of `idct_1d`: the even/odd butterfly with an arithmetic shift
+/// by 15 and the result truncated to `WIDTH` bits.
+fn idct_1d(x: [i64; 8]) -> [i64; 8] {
+    let im0 = A * x[0] + B * x[2] + A * x[4] + C * x[6];
+    let im2 = A * x[0] + C * x[2] - A * x[4] - B * x[6];
+    let im4 = A * x[0] - C * x[2] - A * x[4] + B * x[6];
+    let im6 = A * x[0] - B * x[2] + A * x[4] - C * x[6];
+
+    let im1 = D * x[1] + E * x[3] + F * x[5] + G * x[7];
+    let im3 = E * x[1] - G * x[3] - D * x[5] - F * x[7];
+    let im5 = F * x[1] - D * x[3] + G * x[5] + E * x[7];
+    let im7 = G * x[1] - F * x[3] + E * x[5] - D * x[7];
+
+    [
+        im0 + im1,
+        im2 + im3,
+        im4 + im5,
+        im6 + im7,
+        im6 - im7,
+        im4 - im5,
+        im2 - im3,
+        im0 - im1,
+    ]
+    .map(|v| wrap(v >> 15, WIDTH))
+}
+
+/// Dequantize and inverse transform blocks of natural-order coefficients
+/// bit-exactly with the FPGA `inverse_quantizer` and `idct_2d` modules.
+///
+/// `inverse_quantizer` multiplies by the base luma table with hard-wired
+/// shifts and adds, so that table is used for every block and the stream's
+/// `_table` is ignored. Planes quantized with any other table, such as the
+/// chroma planes or frames at a quality other than 50, come out scaled as
+/// wrongly as on the hardware.
+///
+/// Every intermediate value is truncated to the width of the register that
+/// holds it in `hdl/`, so overflow wraps like the hardware instead of
+/// saturating. Coefficients enter `inverse_quantizer` and the first `idct_1d` pass one
+/// column at a time, the transpose buffer turns the results into rows for
+/// the second pass, and `idct_2d` adds 128 and keeps the low 8 bits of each
+/// sample, so out of range pixels wrap rather than clamp.
+pub fn hw_idct(mut blocks: ArrayViewMut2<i64>, _table: &[i64; 64]) {
+    let table = &LUMA_QUANTIZATION_TABLE;
+    for mut block in blocks.rows_mut() {
+        let mut buffer = [[0i64; 8]; 8];
+
+        for col in 0..8 {
+            let column = std::array::from_fn(|k| {
+                let c = wrap(block[k * 8 + col], WIDTH);
+                wrap(c * table[k * 8 + col], WIDTH)
+            });
+            for (k, v) in idct_1d(column).into_iter().enumerate() {
+                buffer[k][col] = v;
+            }
+        }
+
+        for (row, values) in buffer.into_iter().enumerate() {
+            for (j, v) in idct_1d(values).into_iter().enumerate() {
+                block[row * 8 + j] = (v + 128) & 0xff;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Steps of `inverse_quantizer.sv`, recovered from the shift-and-add
+    /// expression of every output in every column state.
+    fn inverse_quantizer_table() -> [i64; 64] {
+        let source = include_str!("../../hdl/inverse_quantizer.sv");
+        let mut table = [0; 64];
+        let mut column = None;
+        for line in source.lines() {
+            if let Some((_, state)) = line.split_once("3'd") {
+                column = state[..1].parse::<usize>().ok();
+            } else if line.contains("endcase") {
+                column = None;
+            }
+            let (Some(col), Some((target, expr))) = (column, line.split_once("<=")) else {
+                continue;
+            };
+            let Some((_, row)) = target.split_once("o[") else {
+                continue;
+            };
+            let row: usize = row[..1].parse().unwrap();
+            let expr = expr.split(';').next().unwrap();
+            table[row * 8 + col] = expr
+                .split('+')
+                .map(|term| {
+                    let term = term.trim().trim_matches(|c| c == '(' || c == ')');
+                    match term.rsplit_once('<') {
+                        Some((_, shift)) => 1 << shift.parse::<u32>().unwrap(),
+                        None => 1,
+                    }
+                })
+                .sum();
+        }
+        table
+    }
+
+    #[test]
+    fn table_matches_inverse_quantizer() {
+        assert_eq!(inverse_quantizer_table(), LUMA_QUANTIZATION_TABLE);
+    }
+
+    /// Blocks of natural-order coefficients and the samples the FPGA decodes
+    /// them to, found by evaluating the expressions of `inverse_quantizer.sv`,
+    /// `idct_1d.sv` and `idct_2d.sv` at their register widths.
+    const GOLDEN: [([i64; 64], [i64; 64]); 3] = [
+        (
+            [
+                5, -3, 0, 0, 0, 0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0,
+            ],
+            [
+                139, 139, 140, 141, 142, 143, 144, 145, 138, 138, 139, 140, 142, 143, 144, 144,
+                135, 136, 137, 139, 140, 142, 143, 144, 133, 134, 135, 137, 139, 141, 143, 144,
+                130, 131, 133, 135, 138, 140, 142, 143, 127, 128, 130, 133, 136, 139, 142, 143,
+                126, 127, 129, 132, 136, 139, 141, 142, 124, 126, 128, 131, 135, 138, 141, 142,
+            ],
+        ),
+        (
+            [
+                0, 0, 0, 0, 0, 0, 2, 0, 0, 1, -5, 0, 0, 0, 0, 1, 0, 0, -6, 0, -6, -3, 0, 0, 3, 0,
+                0, 0, 0, 0, 0, 2, 0, 0, 0, -3, 0, 3, 0, 0, 0, -6, 0, -3, 0, 0, 0, -5, 0, 4, -5, -6,
+                0, 0, -3, 0, 1, 0, 2, 2, 5, 0, 0, 0,
+            ],
+            [
+                226, 159, 243, 21, 92, 90, 132, 125, 240, 82, 182, 211, 245, 98, 142, 5, 124, 193,
+                32, 99, 249, 207, 210, 188, 246, 47, 169, 238, 67, 1, 201, 172, 72, 95, 29, 76,
+                243, 255, 163, 217, 136, 48, 103, 188, 183, 241, 112, 236, 2, 70, 97, 240, 64, 192,
+                31, 137, 18, 196, 40, 150, 79, 68, 150, 250,
+            ],
+        ),
+        (
+            [
+                60, 0, -40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 25, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 3,
+            ],
+            [
+                2, 19, 72, 58, 56, 221, 166, 105, 204, 13, 1, 104, 10, 36, 171, 159, 163, 160, 52,
+                246, 123, 241, 25, 201, 93, 198, 172, 114, 0, 121, 242, 14, 122, 117, 37, 227, 143,
+                0, 67, 242, 139, 228, 205, 112, 2, 88, 212, 225, 220, 223, 70, 23, 91, 223, 217,
+                143, 252, 35, 48, 86, 28, 245, 150, 111,
+            ],
+        ),
+    ];
+
+    #[test]
+    fn golden_vectors() {
+        // The stream's table must not matter, the hardware ignores it.
+        let table = [1; 64];
+        for (coefficients, samples) in GOLDEN {
+            let mut block = Array2::from_shape_vec((1, 64), coefficients.to_vec()).unwrap();
+            hw_idct(block.view_mut(), &table);
+            assert_eq!(block.as_slice().unwrap(), samples);
+        }
+    }
+}
@@ -0,0 +1,675 @@
+use crate::color::{Matrix, Range};
+use crate::header::{Chroma, Header, HuffmanSpec};
+use crate::{
+    empty_blocks, for_each_symbol, read_magnitude, write_symbols, HuffmanTable, Symbol,
+    SymbolCounts, SCAN_ORDER_TABLE,
+};
+use anyhow::{anyhow, Result};
+use bitstream::{BigEndian, BitReader, BitWrite, BitWriter, HuffmanRead};
+use ndarray::prelude::*;
+use std::io::{Read, Write};
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const APP0: u8 = 0xe0;
+const APP15: u8 = 0xef;
+const COM: u8 = 0xfe;
+const DQT: u8 = 0xdb;
+const DRI: u8 = 0xdd;
+const SOF0: u8 = 0xc0;
+const SOF1: u8 = 0xc1;
+const SOF15: u8 = 0xcf;
+const DHT: u8 = 0xc4;
+const SOS: u8 = 0xda;
+const RST0: u8 = 0xd0;
+const RST7: u8 = 0xd7;
+
+/// Write a marker segment: the marker, the 16-bit length of the segment
+/// including the length field itself, and the body.
+fn write_segment<W>(out: &mut W, marker: u8, body: &[u8]) -> Result<()>
+where
+    W: Write,
+{
+    out.write_all(&[0xff, marker])?;
+    out.write_all(&u16::try_from(body.len() + 2)?.to_be_bytes())?;
+    out.write_all(body)?;
+
+    Ok(())
+}
+
+/// Block indices of each component in the order of a baseline scan.
+///
+/// A colour frame is a single interleaved scan whose MCUs hold `sy` x `sx`
+/// luma blocks in raster order followed by one block of each chroma plane. A
+/// grey frame is a non-interleaved scan of its luma blocks in raster order.
+fn mcu_order(header: &Header) -> Vec<(usize, usize)> {
+    let (height, width) = header.padded_size();
+    let (sy, sx) = header.chroma.subsampling();
+    let (luma_x, chroma_x) = (width / 8, header.chroma_size().1 / 8);
+
+    let mut order = Vec::new();
+    for r in 0..height / (8 * sy) {
+        for c in 0..width / (8 * sx) {
+            for v in 0..sy {
+                for h in 0..sx {
+                    order.push((0, (r * sy + v) * luma_x + c * sx + h));
+                }
+            }
+            if header.chroma != Chroma::Gray {
+                order.push((1, r * chroma_x + c));
+                order.push((2, r * chroma_x + c));
+            }
+        }
+    }
+
+    order
+}
+
+/// Visit the symbols of the blocks of a frame in scan order, with the DC
+/// predictor of each component running across the whole scan.
+fn for_each_scan_symbol<F>(header: &Header, planes: [&Array2<i64>; 3], mut f: F)
+where
+    F: FnMut(Symbol),
+{
+    let mut predictors = [0; 3];
+
+    for (component, n) in mcu_order(header) {
+        let mut block = planes[component].row(n).to_owned();
+        let dc = block[0];
+        block[0] -= predictors[component];
+        predictors[component] = dc;
+        for_each_symbol(block.view().insert_axis(Axis(0)), &mut f);
+    }
+}
+
+/// Entropy code the blocks of a frame in scan order.
+///
+/// The data is padded to a byte boundary with one bits and every `0xff` byte
+/// is followed by a stuffed zero byte so that it cannot be taken for a
+/// marker.
+fn scan_data(
+    header: &Header,
+    planes: [&Array2<i64>; 3],
+    codebook: &HuffmanTable,
+) -> Result<Vec<u8>> {
+    let mut bits = BitWriter::endian(Vec::new(), BigEndian);
+    write_symbols(&mut bits, codebook, |f| {
+        for_each_scan_symbol(header, planes, f)
+    })?;
+
+    while !bits.byte_aligned() {
+        bits.write_bit(true)?;
+    }
+
+    let mut data = Vec::new();
+    for byte in bits.into_writer() {
+        data.push(byte);
+        if byte == 0xff {
+            data.push(0);
+        }
+    }
+
+    Ok(data)
+}
+
+/// Write one frame as a baseline JFIF file.
+///
+/// `planes` hold the quantized Y, U and V blocks of the padded frame in
+/// raster order, with coefficients in zigzag order and absolute DC values,
+/// and `tables` the luma and chroma quantization tables in natural order.
+/// All components share one DC and one AC table, optimal for the symbols of
+/// the scan, so every symbol has a code whatever tables the stream used.
+///
+/// JFIF has no way to signal the colour matrix or range, and viewers assume
+/// full range BT.601, so other streams are shown with slightly shifted
+/// colours.
+pub fn write_jpeg<W>(
+    out: &mut W,
+    header: &Header,
+    planes: [&Array2<i64>; 3],
+    tables: [&[i64; 64]; 2],
+) -> Result<()>
+where
+    W: Write,
+{
+    let (sy, sx) = header.chroma.subsampling();
+    let components: &[(u8, u8, u8)] = match header.chroma {
+        Chroma::Gray => &[(1, 0x11, 0)],
+        _ => &[(1, ((sx << 4) | sy) as u8, 0), (2, 0x11, 1), (3, 0x11, 1)],
+    };
+
+    out.write_all(&[0xff, SOI])?;
+    write_segment(out, APP0, b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00")?;
+
+    let mut dqt = Vec::new();
+    for (id, table) in tables.iter().enumerate().take(components.len().min(2)) {
+        dqt.push(id as u8);
+        dqt.extend(SCAN_ORDER_TABLE.iter().map(|&k| table[k] as u8));
+    }
+    write_segment(out, DQT, &dqt)?;
+
+    let mut sof = vec![8];
+    sof.extend(u16::try_from(header.height)?.to_be_bytes());
+    sof.extend(u16::try_from(header.width)?.to_be_bytes());
+    sof.push(components.len() as u8);
+    for &(id, sampling, table) in components {
+        sof.extend([id, sampling, table]);
+    }
+    write_segment(out, SOF0, &sof)?;
+
+    let mut counts = SymbolCounts::new();
+    for_each_scan_symbol(header, planes, |symbol| counts.count(&symbol));
+    let (dc, ac) = counts.specs();
+    let codebook = HuffmanTable::from_specs(&dc, &ac)?;
+
+    let mut dht = Vec::new();
+    for (class, spec) in [(0x00, &dc), (0x10, &ac)] {
+        let HuffmanSpec { bits, values } = spec;
+        dht.push(class);
+        dht.extend(bits);
+        dht.extend(values);
+    }
+    write_segment(out, DHT, &dht)?;
+
+    let mut sos = vec![components.len() as u8];
+    for &(id, _, _) in components {
+        sos.extend([id, 0x00]);
+    }
+    sos.extend([0, 63, 0]);
+    write_segment(out, SOS, &sos)?;
+
+    out.write_all(&scan_data(header, planes, &codebook)?)?;
+    out.write_all(&[0xff, EOI])?;
+
+    Ok(())
+}
+
+/// A component of a JPEG frame.
+struct Component {
+    id: u8,
+    /// Horizontal and vertical sampling factors.
+    h: usize,
+    v: usize,
+    /// Index of the quantization table.
+    table: usize,
+}
+
+/// Decoder state between the marker segments of a JPEG file.
+#[derive(Default)]
+struct Decoder {
+    tables: [Option<[i64; 64]>; 4],
+    /// DC and AC Huffman tables by destination.
+    huffman: [[Option<HuffmanSpec>; 4]; 2],
+    frame: Option<(Header, Vec<Component>)>,
+    blocks: Option<[Array2<i64>; 3]>,
+    /// Quantization table of every component, fixed by its first scan.
+    component_tables: [Option<[i64; 64]>; 3],
+    restart_interval: usize,
+}
+
+/// Find the marker at `data[pos..]`, skipping fill bytes, and return it with
+/// the position that follows it.
+fn next_marker(data: &[u8], mut pos: usize) -> Result<(u8, usize)> {
+    if data.get(pos) != Some(&0xff) {
+        return Err(anyhow!("Expected a marker at byte {}", pos));
+    }
+    while data.get(pos) == Some(&0xff) {
+        pos += 1;
+    }
+    let marker = *data
+        .get(pos)
+        .ok_or_else(|| anyhow!("File ends without an EOI marker"))?;
+
+    Ok((marker, pos + 1))
+}
+
+/// Body of the marker segment whose length field is at `data[pos..]`.
+fn segment_body(data: &[u8], pos: usize) -> Result<&[u8]> {
+    let length = match data.get(pos..pos + 2) {
+        Some(&[high, low]) => u16::from_be_bytes([high, low]) as usize,
+        _ => 0,
+    };
+    if length < 2 || pos + length > data.len() {
+        return Err(anyhow!("Truncated marker segment at byte {}", pos));
+    }
+
+    Ok(&data[pos + 2..pos + length])
+}
+
+/// Split the entropy coded data from `data[pos..]` into its restart
+/// intervals, with stuffed zero bytes removed, and return them with the
+/// position of the marker that ends the scan.
+fn scan_intervals(data: &[u8], mut pos: usize) -> Result<(Vec<Vec<u8>>, usize)> {
+    let mut intervals = vec![Vec::new()];
+
+    loop {
+        let byte = *data
+            .get(pos)
+            .ok_or_else(|| anyhow!("File ends inside a scan"))?;
+        if byte != 0xff {
+            intervals.last_mut().unwrap().push(byte);
+            pos += 1;
+            continue;
+        }
+
+        match data.get(pos + 1) {
+            Some(0) => {
+                intervals.last_mut().unwrap().push(0xff);
+                pos += 2;
+            }
+            Some(RST0..=RST7) => {
+                intervals.push(Vec::new());
+                pos += 2;
+            }
+            Some(0xff) => pos += 1,
+            Some(_) => return Ok((intervals, pos)),
+            None => return Err(anyhow!("File ends inside a scan")),
+        }
+    }
+}
+
+/// Decode the coefficients of one block in zigzag order. `predictor` holds
+/// the DC value of the previous block of the component and is updated to
+/// that of this block.
+fn read_block<R>(
+    reader: &mut BitReader<R, BigEndian>,
+    codebook: &HuffmanTable,
+    predictor: &mut i64,
+    mut block: ArrayViewMut1<i64>,
+) -> Result<()>
+where
+    R: Read,
+{
+    let size = reader.read_huffman(&codebook.dc_read)?;
+    if !(0..=11).contains(&size) {
+        return Err(anyhow!("Invalid DC code in scan"));
+    }
+    *predictor += read_magnitude(reader, size)?;
+    block[0] = *predictor;
+
+    let mut position = 1;
+    while position < 64 {
+        let (run, size) = reader.read_huffman(&codebook.ac_read)?;
+        if (run, size) == (0, 0) {
+            break;
+        }
+        if run < 0 || size > 10 || (size == 0 && run != 15) {
+            return Err(anyhow!("Invalid AC code in scan"));
+        }
+
+        position += run as usize;
+        if position >= 64 {
+            return Err(anyhow!("AC run past the end of a block"));
+        }
+        block[position] = read_magnitude(reader, size)?;
+        position += 1;
+    }
+
+    Ok(())
+}
+
+impl Decoder {
+    fn read_dqt(&mut self, mut body: &[u8]) -> Result<()> {
+        while let [info, rest @ ..] = body {
+            let (precision, id) = ((info >> 4) as usize, (info & 0xf) as usize);
+            let size = 64 * (precision + 1);
+            if precision > 1 || id > 3 || rest.len() < size {
+                return Err(anyhow!("Invalid DQT segment"));
+            }
+
+            let mut table = [0; 64];
+            for (i, &k) in SCAN_ORDER_TABLE.iter().enumerate() {
+                table[k] = match precision {
+                    0 => rest[i] as i64,
+                    _ => u16::from_be_bytes([rest[2 * i], rest[2 * i + 1]]) as i64,
+                };
+            }
+            self.tables[id] = Some(table);
+            body = &rest[size..];
+        }
+
+        Ok(())
+    }
+
+    fn read_dht(&mut self, mut body: &[u8]) -> Result<()> {
+        while let [info, rest @ ..] = body {
+            let (class, id) = ((info >> 4) as usize, (info & 0xf) as usize);
+            if class > 1 || id > 3 || rest.len() < 16 {
+                return Err(anyhow!("Invalid DHT segment"));
+            }
+
+            let bits: [u8; 16] = rest[..16].try_into()?;
+            let count = bits.iter().map(|&n| n as usize).sum::<usize>();
+            let values = rest
+                .get(16..16 + count)
+                .ok_or_else(|| anyhow!("Invalid DHT segment"))?;
+            self.huffman[class][id] = Some(HuffmanSpec {
+                bits,
+                values: values.to_vec(),
+            });
+            body = &rest[16 + count..];
+        }
+
+        Ok(())
+    }
+
+    /// Read the frame header and describe the image as a single-frame stream.
+    ///
+    /// A three-component frame must have 1x1 sampled chroma and luma sampled
+    /// as one of the [`Chroma`] layouts. The colour matrix and range are
+    /// those of JFIF.
+    fn read_sof(&mut self, body: &[u8]) -> Result<()> {
+        let [precision, h0, h1, w0, w1, count, rest @ ..] = body else {
+            return Err(anyhow!("Invalid SOF segment"));
+        };
+        if *precision != 8 {
+            return Err(anyhow!("Unsupported sample precision: {}", precision));
+        }
+        let height = u16::from_be_bytes([*h0, *h1]) as usize;
+        let width = u16::from_be_bytes([*w0, *w1]) as usize;
+        if height == 0 || width == 0 {
+            return Err(anyhow!("Unsupported image size: {}x{}", width, height));
+        }
+        if rest.len() != 3 * *count as usize {
+            return Err(anyhow!("Invalid SOF segment"));
+        }
+
+        let mut components = rest
+            .chunks(3)
+            .map(|c| Component {
+                id: c[0],
+                h: (c[1] >> 4) as usize,
+                v: (c[1] & 0xf) as usize,
+                table: (c[2] & 0x3) as usize,
+            })
+            .collect::<Vec<_>>();
+
+        let chroma = match components.as_mut_slice() {
+            // The sampling factors of a lone component have no effect.
+            [y] => {
+                (y.h, y.v) = (1, 1);
+                Chroma::Gray
+            }
+            [y, cb, cr] if (cb.h, cb.v, cr.h, cr.v) == (1, 1, 1, 1) => match (y.v, y.h) {
+                (1, 1) => Chroma::Yuv444,
+                (1, 2) => Chroma::Yuv422,
+                (2, 2) => Chroma::Yuv420,
+                (v, h) => return Err(anyhow!("Unsupported luma sampling: {}x{}", h, v)),
+            },
+            _ => return Err(anyhow!("Unsupported component layout")),
+        };
+
+        let header = Header {
+            width,
+            height,
+            frame_rate: (1, 1),
+            frame_count: 1,
+            gop: 1,
+            chroma,
+            matrix: Matrix::Bt601,
+            range: Range::Full,
+            index_offset: 0,
+            luma_table: [1; 64],
+            chroma_table: [1; 64],
+            huffman: None,
+            dc_prediction: true,
+            restart_interval: None,
+            skip_blocks: false,
+            frame_quality: false,
+        };
+        self.blocks = Some(empty_blocks(&header));
+        self.frame = Some((header, components));
+
+        Ok(())
+    }
+
+    /// Decode the scan whose SOS segment is `body` from its restart
+    /// `intervals`.
+    ///
+    /// A scan of one component codes its blocks in raster order, covering the
+    /// component but not the padding of the last MCU row and column. A scan
+    /// of several components is coded in MCUs of `v` x `h` blocks of each.
+    fn read_scan(&mut self, body: &[u8], intervals: Vec<Vec<u8>>) -> Result<()> {
+        let (header, components) = self
+            .frame
+            .as_ref()
+            .ok_or_else(|| anyhow!("Scan before the frame header"))?;
+        let blocks = self.blocks.as_mut().unwrap();
+
+        let [count, rest @ ..] = body else {
+            return Err(anyhow!("Invalid SOS segment"));
+        };
+        let count = *count as usize;
+        if rest.len() != 2 * count + 3 || count == 0 {
+            return Err(anyhow!("Invalid SOS segment"));
+        }
+        if rest[2 * count..] != [0, 63, 0] {
+            return Err(anyhow!("Only baseline sequential scans are supported"));
+        }
+
+        let mut scan = Vec::new();
+        for selector in rest[..2 * count].chunks(2) {
+            let c = components
+                .iter()
+                .position(|component| component.id == selector[0])
+                .ok_or_else(|| anyhow!("Scan of unknown component {}", selector[0]))?;
+            let spec = |class: usize, id: u8| {
+                self.huffman[class][id as usize & 0x3]
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Scan uses an undefined Huffman table"))
+            };
+            let codebook =
+                HuffmanTable::from_specs(spec(0, selector[1] >> 4)?, spec(1, selector[1] & 0xf)?)?;
+
+            let table = self.tables[components[c].table]
+                .ok_or_else(|| anyhow!("Scan uses an undefined quantization table"))?;
+            self.component_tables[c].get_or_insert(table);
+            scan.push((c, codebook));
+        }
+
+        // Width in blocks of each plane, and of each component's own area.
+        let (height, width) = (header.height, header.width);
+        let grid = [
+            header.padded_size().1,
+            header.chroma_size().1,
+            header.chroma_size().1,
+        ]
+        .map(|w| w / 8);
+        let (h_max, v_max) = (components[0].h, components[0].v);
+
+        let mut mcus = Vec::new();
+        if let [(c, _)] = scan[..] {
+            let component = &components[c];
+            let rows = (height * component.v).div_ceil(v_max).div_ceil(8);
+            let cols = (width * component.h).div_ceil(h_max).div_ceil(8);
+            for r in 0..rows {
+                for col in 0..cols {
+                    mcus.push(vec![(0, r * grid[c] + col)]);
+                }
+            }
+        } else {
+            for r in 0..height.div_ceil(8 * v_max) {
+                for col in 0..width.div_ceil(8 * h_max) {
+                    let mut mcu = Vec::new();
+                    for (s, &(c, _)) in scan.iter().enumerate() {
+                        let Component { h, v, .. } = components[c];
+                        for y in 0..v {
+                            for x in 0..h {
+                                mcu.push((s, (r * v + y) * grid[c] + col * h + x));
+                            }
+                        }
+                    }
+                    mcus.push(mcu);
+                }
+            }
+        }
+
+        let interval = match self.restart_interval {
+            0 => mcus.len(),
+            n => n,
+        };
+        let expected = mcus.len().div_ceil(interval);
+        if intervals.len() < expected {
+            return Err(anyhow!(
+                "Scan has {} restart intervals, expected {}",
+                intervals.len(),
+                expected
+            ));
+        }
+
+        for (mcus, data) in mcus.chunks(interval).zip(intervals) {
+            let mut reader = BitReader::endian(data.as_slice(), BigEndian);
+            let mut predictors = [0; 3];
+            for &(s, n) in mcus.iter().flatten() {
+                let (c, codebook) = &scan[s];
+                read_block(
+                    &mut reader,
+                    codebook,
+                    &mut predictors[*c],
+                    blocks[*c].row_mut(n),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Coefficients of a decoded JPEG file.
+pub struct JpegImage {
+    /// A single-frame header describing the image, with the quantization
+    /// tables of its first two components.
+    pub header: Header,
+    /// The quantized blocks of each plane in the layout [`write_jpeg`] takes.
+    pub blocks: [Array2<i64>; 3],
+    /// The quantization table of each plane in natural order.
+    pub tables: [[i64; 64]; 3],
+}
+
+/// Read a baseline JFIF/JPEG file, the inverse of [`write_jpeg`].
+///
+/// Any number of scans, restart intervals and per-component Huffman and
+/// quantization tables are allowed, but not progressive, lossless or
+/// arithmetic coded files.
+pub fn read_jpeg(data: &[u8]) -> Result<JpegImage> {
+    if !data.starts_with(&[0xff, SOI]) {
+        return Err(anyhow!("Not a JPEG file"));
+    }
+
+    let mut decoder = Decoder::default();
+    let mut pos = 2;
+    loop {
+        let (marker, start) = next_marker(data, pos)?;
+        if marker == EOI {
+            break;
+        }
+        let body = segment_body(data, start)?;
+        pos = start + 2 + body.len();
+
+        match marker {
+            DQT => decoder.read_dqt(body)?,
+            DHT => decoder.read_dht(body)?,
+            SOF0 | SOF1 => {
+                if decoder.frame.is_some() {
+                    return Err(anyhow!("File has more than one frame"));
+                }
+                decoder.read_sof(body)?;
+            }
+            DRI => {
+                let &[high, low] = body else {
+                    return Err(anyhow!("Invalid DRI segment"));
+                };
+                decoder.restart_interval = u16::from_be_bytes([high, low]) as usize;
+            }
+            SOS => {
+                let (intervals, end) = scan_intervals(data, pos)?;
+                decoder.read_scan(body, intervals)?;
+                pos = end;
+            }
+            APP0..=APP15 | COM => {}
+            SOF0..=SOF15 => return Err(anyhow!("Only baseline JPEG files are supported")),
+            _ => return Err(anyhow!("Unsupported marker: 0x{:02x}", marker)),
+        }
+    }
+
+    let (mut header, components) = decoder
+        .frame
+        .ok_or_else(|| anyhow!("File has no frame header"))?;
+    let blocks = decoder.blocks.unwrap();
+    let mut tables = [[1; 64]; 3];
+    for (c, table) in tables.iter_mut().enumerate().take(components.len()) {
+        *table = decoder.component_tables[c]
+            .ok_or_else(|| anyhow!("Component {} has no scan", components[c].id))?;
+    }
+    (header.luma_table, header.chroma_table) = (tables[0], tables[1]);
+
+    Ok(JpegImage {
+        header,
+        blocks,
+        tables,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let luma_table = std::array::from_fn(|i| 1 + i as i64);
+        let chroma_table = std::array::from_fn(|i| 99 - i as i64);
+        for chroma in [Chroma::Yuv420, Chroma::Yuv422, Chroma::Yuv444, Chroma::Gray] {
+            let header = Header {
+                width: 37,
+                height: 23,
+                frame_rate: (1, 1),
+                frame_count: 1,
+                gop: 1,
+                chroma,
+                matrix: Matrix::Bt601,
+                range: Range::Full,
+                index_offset: 0,
+                luma_table,
+                chroma_table,
+                huffman: None,
+                dc_prediction: true,
+                restart_interval: None,
+                skip_blocks: false,
+                frame_quality: false,
+            };
+
+            // Sparse coefficients with runs of zeros and sizes up to the
+            // largest a baseline scan codes.
+            let mut seed = 1u32;
+            let mut next = || {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as i64
+            };
+            let mut blocks = empty_blocks(&header);
+            for plane in &mut blocks {
+                for mut block in plane.rows_mut() {
+                    block[0] = next() % 2048 - 1024;
+                    for i in 1..64 {
+                        if next() % 4 == 0 {
+                            block[i] = (next() % 2047 - 1023) >> (next() % 10);
+                        }
+                    }
+                }
+            }
+
+            let mut data = Vec::new();
+            let planes = [&blocks[0], &blocks[1], &blocks[2]];
+            write_jpeg(&mut data, &header, planes, [&luma_table, &chroma_table]).unwrap();
+
+            let image = read_jpeg(&data).unwrap();
+            assert_eq!(image.header.width, header.width);
+            assert_eq!(image.header.height, header.height);
+            assert_eq!(image.header.chroma, chroma);
+            assert_eq!(image.blocks, blocks);
+            assert_eq!(image.tables[0], luma_table);
+            if chroma != Chroma::Gray {
+                assert_eq!(image.tables[1..], [chroma_table; 2]);
+            }
+        }
+    }
+}
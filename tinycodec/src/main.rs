@@ -1,21 +1,57 @@
 extern crate bitstream_io as bitstream;
 extern crate video_rs as video;
 
+mod avi;
+mod color;
+mod dct;
+mod deblock;
+mod header;
+mod hwidct;
+mod jpeg;
+mod motion;
+mod rate;
+mod raw;
+mod rdo;
+mod resample;
+mod y4m;
+
 use anyhow::{anyhow, Result};
+use avi::AviWriter;
 use bitstream::{
     huffman::{compile_read_tree, compile_write_tree, ReadHuffmanTree, WriteHuffmanTree},
     BigEndian, BitRead, BitReader, BitWrite, BitWriter, HuffmanRead, HuffmanWrite,
 };
-use clap::{Parser, Subcommand};
-use core::str;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use color::{rgb_to_yuv, yuv_to_rgb, Matrix, Range};
+use dct::{
+    fdct, fdct_quotients, fdct_residual, fdct_residual_quotients, idct, idct_residual, matrix_fdct,
+    matrix_idct,
+};
+use deblock::deblock;
+use header::{
+    frame_rate_to_rational, join_slices, read_frame, read_index, split_slices, write_frame,
+    write_index, Chroma, Header, HuffmanSpec,
+};
+use hwidct::hw_idct;
+use jpeg::{read_jpeg, write_jpeg, JpegImage};
 use kdam::{tqdm, TqdmIterator};
+use motion::{compensate, estimate, macroblocks};
 use ndarray::prelude::*;
+use rate::{search, RateControl};
+use raw::{parse_size, write_raw_frame, PixFmt, RawReader};
+use resample::{downsample, upsample, Downsample, Upsample};
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    iter,
     path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Instant,
 };
 use video::{decode::Decoder, encode::Settings, Encoder, Frame, Time};
+use y4m::{Y4mReader, Y4mWriter};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,18 +62,223 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Encode {
-        #[arg(value_name = "infile")]
-        infile: String,
-        #[arg(value_name = "outfile")]
-        outfile: String,
-    },
-    Decode {
-        #[arg(value_name = "infile")]
-        infile: String,
-        #[arg(value_name = "outfile")]
-        outfile: String,
-    },
+    Encode(EncodeArgs),
+    Decode(DecodeArgs),
+    /// Write frames of a stream as baseline JPEG files.
+    ExportJpeg(ExportJpegArgs),
+    /// Decode a baseline JPEG file to a PPM image.
+    ImportJpeg(ImportJpegArgs),
+    /// Time the fast transforms against the dense matrix reference.
+    Bench(BenchArgs),
+}
+
+#[derive(Args)]
+struct EncodeArgs {
+    #[arg(value_name = "infile")]
+    infile: String,
+    #[arg(value_name = "outfile")]
+    outfile: String,
+    /// Quality factor in the style of libjpeg, 50 keeps the base tables.
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+    /// Build optimal Huffman tables for this stream with an extra pass.
+    #[arg(long)]
+    optimize: bool,
+    /// How DC coefficients are coded.
+    #[arg(long, value_enum, default_value_t = DcCoding::Predicted)]
+    dc: DcCoding,
+    /// Chroma subsampling of the coded stream.
+    #[arg(long, value_enum, default_value_t = Chroma::Yuv420)]
+    chroma: Chroma,
+    /// Filter used to subsample the chroma planes.
+    #[arg(long, value_enum, default_value_t = Downsample::Box)]
+    downsample: Downsample,
+    /// Matrix used to convert the input from RGB to YUV. For Y4M input, the
+    /// matrix its planes were made with.
+    #[arg(long, value_enum, default_value_t = Matrix::Bt601)]
+    matrix: Matrix,
+    /// Range of the coded YUV values. For Y4M input, the range of its planes
+    /// unless the file has an `XCOLORRANGE` tag.
+    #[arg(long, value_enum, default_value_t = Range::Full)]
+    range: Range,
+    /// Number of GOPs encoded in parallel, defaults to the number of CPUs.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Distance between I-frames, the frames in between are coded as P-frames.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    gop: u16,
+    /// Split each plane into independently decodable slices of this many MCU rows.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    restart_interval: Option<u16>,
+    /// How P-frames are predicted from the previous frame.
+    #[arg(long, value_enum, default_value_t = Inter::Motion)]
+    inter: Inter,
+    /// Largest sum of absolute differences from the previous frame at which
+    /// an 8x8 block is skipped by `--inter skip`.
+    #[arg(long, default_value_t = 256)]
+    skip_threshold: u32,
+    /// Target bitrate in kbit/s, met by choosing the quality of every frame.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "frame_budget")]
+    bitrate: Option<u32>,
+    /// Size in bytes of the buffer smoothing `--bitrate`, defaults to one second.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), requires = "bitrate")]
+    buffer: Option<u32>,
+    /// Largest payload in bytes of every frame, met by choosing its quality.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    frame_budget: Option<u32>,
+    /// Pad every frame to exactly `--frame-budget` bytes, dropping the high
+    /// frequencies of frames that do not fit at any quality.
+    #[arg(long, requires = "frame_budget")]
+    constant_size: bool,
+    /// Lower or zero coefficients whose bits cost more than their distortion.
+    #[arg(long)]
+    rdo: bool,
+    /// Distortion traded for each bit saved by `--rdo`, in squared DC
+    /// quantizer steps of the block's table. The default is close to ln 2 / 6,
+    /// the rate-distortion slope of a uniform quantizer at high rate.
+    #[arg(long, default_value_t = 0.12, requires = "rdo")]
+    lambda: f64,
+    /// Read the input as headerless frames of this size, bypassing ffmpeg.
+    #[arg(long, value_name = "WxH", value_parser = parse_size)]
+    raw: Option<(usize, usize)>,
+    /// Layout of the frames of a `--raw` input.
+    #[arg(long, value_enum, default_value_t = PixFmt::Yuv420p, requires = "raw")]
+    pix_fmt: PixFmt,
+    /// Frame rate of a `--raw` input.
+    #[arg(long, default_value_t = 30.0, requires = "raw")]
+    frame_rate: f32,
+}
+
+#[derive(Args)]
+struct DecodeArgs {
+    #[arg(value_name = "infile")]
+    infile: String,
+    #[arg(value_name = "outfile")]
+    outfile: String,
+    /// Index of the first frame to decode.
+    #[arg(long, default_value_t = 0)]
+    start: usize,
+    /// Number of frames to decode, defaults to the rest of the stream.
+    #[arg(long)]
+    count: Option<usize>,
+    /// Filter used to restore subsampled chroma planes.
+    #[arg(long, value_enum, default_value_t = Upsample::Bilinear)]
+    upsample: Upsample,
+    /// Inverse transform used to reconstruct the blocks of I-frames. The
+    /// encoder predicts P-frames from the `float` reconstruction, so `hw`
+    /// drifts until the next I-frame.
+    #[arg(long, value_enum, default_value_t = Idct::Float)]
+    idct: Idct,
+    /// Number of slices decoded in parallel, defaults to the number of CPUs.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+    /// Smooth block edges in the output. P-frames are still predicted from
+    /// the unfiltered frames, as in the encoder.
+    #[arg(long)]
+    deblock: bool,
+    /// Codec and container of the output.
+    #[arg(long, value_enum, default_value_t = Format::H264)]
+    format: Format,
+    /// Write headerless frames in the layout of `--pix-fmt` instead.
+    #[arg(long, conflicts_with = "format")]
+    raw: bool,
+    /// Layout of the frames written by `--raw`.
+    #[arg(long, value_enum, default_value_t = PixFmt::Yuv420p, requires = "raw")]
+    pix_fmt: PixFmt,
+}
+
+#[derive(Args)]
+struct ExportJpegArgs {
+    #[arg(value_name = "infile")]
+    infile: String,
+    /// Directory the files are written to, one `frame_NNNNN.jpg` per frame.
+    #[arg(value_name = "outdir")]
+    outdir: String,
+    /// Index of the first frame to export.
+    #[arg(long, default_value_t = 0)]
+    start: usize,
+    /// Number of frames to export, defaults to the rest of the stream.
+    #[arg(long)]
+    count: Option<usize>,
+    /// Number of slices decoded in parallel, defaults to the number of CPUs.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+}
+
+#[derive(Args)]
+struct ImportJpegArgs {
+    #[arg(value_name = "infile")]
+    infile: String,
+    /// Binary PPM image the decoded picture is written to.
+    #[arg(value_name = "outfile")]
+    outfile: String,
+    /// Filter used to restore subsampled chroma planes.
+    #[arg(long, value_enum, default_value_t = Upsample::Bilinear)]
+    upsample: Upsample,
+    /// Inverse transform used to reconstruct the blocks. `hw` matches the
+    /// output of the FPGA decoder bit for bit.
+    #[arg(long, value_enum, default_value_t = Idct::Float)]
+    idct: Idct,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Number of random 8x8 blocks to transform.
+    #[arg(long, default_value_t = 100_000)]
+    blocks: usize,
+    /// Quality factor of the quantization tables.
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+}
+
+/// Coding mode for the DC coefficient of each block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DcCoding {
+    /// Code every DC coefficient on its own.
+    Absolute,
+    /// Code the difference from the previous block's DC coefficient.
+    Predicted,
+}
+
+/// Prediction mode of P-frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Inter {
+    /// Code the residual after block motion compensation.
+    Motion,
+    /// Copy unchanged blocks from the previous frame and code the rest as in
+    /// an I-frame.
+    Skip,
+}
+
+/// Signature shared by the forward and inverse block transforms.
+type Transform = fn(ArrayViewMut2<i64>, &[i64; 64]);
+
+/// Signature of the unrounded forward transforms used by `--rdo`.
+type Quotients = fn(ArrayView2<i64>, &[i64; 64]) -> Array2<f64>;
+
+/// Output format of `decode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// H.264 through ffmpeg, in the container implied by the file name.
+    H264,
+    /// Motion JPEG in an AVI file, with every frame coded as by
+    /// `export-jpeg`. The chroma planes stay subsampled.
+    MjpegAvi,
+    /// Uncompressed YUV4MPEG2 with the decoded planes in the chroma layout
+    /// of the stream.
+    Y4m,
+}
+
+/// Implementation of the inverse DCT used by the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Idct {
+    /// Double precision transform with rounding and clamping.
+    Float,
+    /// Fixed-point model of the FPGA pipeline, bit-exact with
+    /// `hdl/inverse_quantizer.sv` and `hdl/idct_2d.sv`. The hardware always
+    /// dequantizes with the base luma table, so only luma at quality 50
+    /// decodes correctly.
+    Hw,
 }
 
 struct HuffmanTable {
@@ -45,6 +286,10 @@ struct HuffmanTable {
     ac_write: WriteHuffmanTree<BigEndian, (i64, i64)>,
     dc_read: Box<[ReadHuffmanTree<BigEndian, i64>]>,
     ac_read: Box<[ReadHuffmanTree<BigEndian, (i64, i64)>]>,
+    /// Code length of every DC symbol and `(run << 4) | size` AC symbol,
+    /// zero for symbols without a code.
+    dc_lengths: [u32; 256],
+    ac_lengths: [u32; 256],
 }
 
 impl HuffmanTable {
@@ -432,6 +677,59 @@ impl HuffmanTable {
             ),
         ];
 
+        Self::from_tables(dc_table, ac_table)
+    }
+
+    /// Build the codebook for a stream, using its own Huffman tables if the
+    /// header carries them and the built-in tables otherwise.
+    fn for_header(header: &Header) -> Result<Self> {
+        match &header.huffman {
+            Some((dc, ac)) => Self::from_specs(dc, ac),
+            None => Self::new(),
+        }
+    }
+
+    /// Build a codebook from DHT-style table specifications.
+    fn from_specs(dc: &HuffmanSpec, ac: &HuffmanSpec) -> Result<Self> {
+        let dc_table = canonical_codes(dc)?
+            .into_iter()
+            .map(|(symbol, code)| (symbol.map_or(-1, |s| s as i64), code))
+            .collect();
+        let ac_table = canonical_codes(ac)?
+            .into_iter()
+            .map(|(symbol, code)| {
+                let symbol = symbol.map_or((-1, -1), |s| ((s >> 4) as i64, (s & 0xf) as i64));
+                (symbol, code)
+            })
+            .collect();
+
+        Self::from_tables(dc_table, ac_table)
+    }
+
+    fn from_tables(
+        dc_table: Vec<(i64, Vec<u8>)>,
+        ac_table: Vec<((i64, i64), Vec<u8>)>,
+    ) -> Result<Self> {
+        let dc_codes: Vec<(u8, &[u8])> = dc_table
+            .iter()
+            .filter(|(symbol, _)| *symbol >= 0)
+            .map(|(symbol, code)| (*symbol as u8, code.as_slice()))
+            .collect();
+        let ac_codes: Vec<(u8, &[u8])> = ac_table
+            .iter()
+            .filter(|((run, _), _)| *run >= 0)
+            .map(|((run, size), code)| (((run << 4) | size) as u8, code.as_slice()))
+            .collect();
+
+        let mut dc_lengths = [0; 256];
+        for &(symbol, code) in &dc_codes {
+            dc_lengths[symbol as usize] = code.len() as u32;
+        }
+        let mut ac_lengths = [0; 256];
+        for &(symbol, code) in &ac_codes {
+            ac_lengths[symbol as usize] = code.len() as u32;
+        }
+
         let dc_write = compile_write_tree::<BigEndian, i64>(dc_table.clone())?;
         let ac_write = compile_write_tree::<BigEndian, (i64, i64)>(ac_table.clone())?;
         let dc_read = compile_read_tree::<BigEndian, i64>(dc_table)?;
@@ -442,202 +740,246 @@ impl HuffmanTable {
             ac_write,
             dc_read,
             ac_read,
+            dc_lengths,
+            ac_lengths,
         })
     }
+
+    /// Number of bits [`write_symbol`] spends on a symbol, or `None` if the
+    /// codebook has no code for it.
+    fn symbol_bits(&self, symbol: &Symbol) -> Option<u32> {
+        let (length, size) = match *symbol {
+            Symbol::Dc(size, _) => (self.dc_lengths.get(size as usize)?, size),
+            Symbol::Ac((run, size), _) => {
+                (self.ac_lengths.get(((run << 4) | size) as usize)?, size)
+            }
+        };
+        (*length > 0).then_some(length + size as u32)
+    }
 }
 
-fn fdct(mut blocks: ArrayViewMut2<i64>) {
-    let (num_blocks, _) = blocks.dim();
-    let t = array![
-        [
-            0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339,
-            0.35355339,
-        ],
-        [
-            0.49039264,
-            0.41573481,
-            0.27778512,
-            0.09754516,
-            -0.09754516,
-            -0.27778512,
-            -0.41573481,
-            -0.49039264,
-        ],
-        [
-            0.46193977,
-            0.19134172,
-            -0.19134172,
-            -0.46193977,
-            -0.46193977,
-            -0.19134172,
-            0.19134172,
-            0.46193977,
-        ],
-        [
-            0.41573481,
-            -0.09754516,
-            -0.49039264,
-            -0.27778512,
-            0.27778512,
-            0.49039264,
-            0.09754516,
-            -0.41573481,
-        ],
-        [
-            0.35355339,
-            -0.35355339,
-            -0.35355339,
-            0.35355339,
-            0.35355339,
-            -0.35355339,
-            -0.35355339,
-            0.35355339,
-        ],
-        [
-            0.27778512,
-            -0.49039264,
-            0.09754516,
-            0.41573481,
-            -0.41573481,
-            -0.09754516,
-            0.49039264,
-            -0.27778512,
-        ],
-        [
-            0.19134172,
-            -0.46193977,
-            0.46193977,
-            -0.19134172,
-            -0.19134172,
-            0.46193977,
-            -0.46193977,
-            0.19134172,
-        ],
-        [
-            0.09754516,
-            -0.27778512,
-            0.41573481,
-            -0.49039264,
-            0.49039264,
-            -0.41573481,
-            0.27778512,
-            -0.09754516,
-        ],
-    ];
-    let mut block = Array2::<f64>::zeros((8, 8));
-    let tt = t.t();
+/// Assign canonical JPEG codes to the symbols of a Huffman table spec.
+///
+/// The codes left unused at the end of the longest length (in a JPEG table
+/// that is at least the all-ones code) are returned with a `None` symbol, so
+/// that the resulting tree is complete like the built-in tables.
+fn canonical_codes(spec: &HuffmanSpec) -> Result<Vec<(Option<u8>, Vec<u8>)>> {
+    let to_bits = |code: u32, len: u32| (0..len).rev().map(|i| (code >> i) as u8 & 1).collect();
+
+    let mut codes = Vec::with_capacity(spec.values.len() + 1);
+    let mut symbols = spec.values.iter();
+    let mut code = 0u32;
+    let mut len = 0u32;
+
+    for (i, &count) in spec.bits.iter().enumerate() {
+        if count > 0 {
+            code <<= i as u32 + 1 - len;
+            len = i as u32 + 1;
+        }
+        for _ in 0..count {
+            let symbol = symbols
+                .next()
+                .ok_or_else(|| anyhow!("Huffman table has fewer symbols than codes"))?;
+            codes.push((Some(*symbol), to_bits(code, len)));
+            code += 1;
+        }
+    }
 
-    blocks -= 128;
+    if len == 0 {
+        return Err(anyhow!("Huffman table is empty"));
+    }
+    if code > 1 << len {
+        return Err(anyhow!("Huffman table is over-subscribed"));
+    }
 
-    for n in 0..num_blocks {
-        block.indexed_iter_mut().for_each(|((i, j), v)| {
-            *v = blocks[(n, i * 8 + j)] as f64;
-        });
-        let x = t.dot(&block.dot(&tt));
-        blocks
-            .slice_mut(s![n, ..])
-            .indexed_iter_mut()
-            .for_each(|(i, v)| {
-                *v = (x[[i / 8, i % 8]] / QUANTIZATION_TABLE[i] as f64).round() as i64;
-            })
+    // Cover the remaining code space with as few filler codes as possible.
+    while code < 1 << len {
+        let span = code.trailing_zeros().min(len);
+        codes.push((None, to_bits(code >> span, len - span)));
+        code += 1 << span;
     }
+
+    Ok(codes)
 }
 
-fn idct(mut blocks: ArrayViewMut2<i64>) {
-    let (num_blocks, _) = blocks.dim();
-    let mut block = Array2::<f64>::zeros((8, 8));
-    let t = array![
-        [
-            0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339, 0.35355339,
-            0.35355339,
-        ],
-        [
-            0.49039264,
-            0.41573481,
-            0.27778512,
-            0.09754516,
-            -0.09754516,
-            -0.27778512,
-            -0.41573481,
-            -0.49039264,
-        ],
-        [
-            0.46193977,
-            0.19134172,
-            -0.19134172,
-            -0.46193977,
-            -0.46193977,
-            -0.19134172,
-            0.19134172,
-            0.46193977,
-        ],
-        [
-            0.41573481,
-            -0.09754516,
-            -0.49039264,
-            -0.27778512,
-            0.27778512,
-            0.49039264,
-            0.09754516,
-            -0.41573481,
-        ],
-        [
-            0.35355339,
-            -0.35355339,
-            -0.35355339,
-            0.35355339,
-            0.35355339,
-            -0.35355339,
-            -0.35355339,
-            0.35355339,
-        ],
-        [
-            0.27778512,
-            -0.49039264,
-            0.09754516,
-            0.41573481,
-            -0.41573481,
-            -0.09754516,
-            0.49039264,
-            -0.27778512,
-        ],
-        [
-            0.19134172,
-            -0.46193977,
-            0.46193977,
-            -0.19134172,
-            -0.19134172,
-            0.46193977,
-            -0.46193977,
-            0.19134172,
-        ],
-        [
-            0.09754516,
-            -0.27778512,
-            0.41573481,
-            -0.49039264,
-            0.49039264,
-            -0.41573481,
-            0.27778512,
-            -0.09754516,
-        ],
-    ];
-    let tt = t.t();
+/// Build a length-limited optimal Huffman table from symbol frequencies.
+///
+/// This is the procedure from ITU-T T.81 Annex K.2 as implemented by libjpeg:
+/// a reserved symbol with a count of one is added so that no real symbol is
+/// assigned the all-ones code, code lengths are found by repeatedly merging
+/// the two least frequent subtrees, and codes longer than 16 bits are folded
+/// back into shorter lengths.
+fn optimal_huffman_spec(counts: &[u64; 256]) -> HuffmanSpec {
+    let mut freq = [0u64; 257];
+    freq[..256].copy_from_slice(counts);
+    if counts.iter().all(|&n| n == 0) {
+        // An empty table still needs one code.
+        freq[0] = 1;
+    }
+    freq[256] = 1;
+
+    let mut codesize = [0usize; 257];
+    let mut others = [None::<usize>; 257];
+
+    let least = |freq: &[u64; 257], skip: Option<usize>| {
+        let mut best = None;
+        let mut min = u64::MAX;
+        for (i, &f) in freq.iter().enumerate() {
+            if f > 0 && f <= min && Some(i) != skip {
+                min = f;
+                best = Some(i);
+            }
+        }
+        best
+    };
+
+    while let (Some(mut c1), Some(mut c2)) = {
+        let c1 = least(&freq, None);
+        (c1, least(&freq, c1))
+    } {
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+
+        codesize[c1] += 1;
+        while let Some(next) = others[c1] {
+            c1 = next;
+            codesize[c1] += 1;
+        }
+        others[c1] = Some(c2);
 
-    for n in 0..num_blocks {
-        block.indexed_iter_mut().for_each(|((i, j), v)| {
-            *v = (blocks[(n, i * 8 + j)] * QUANTIZATION_TABLE[i * 8 + j]) as f64;
-        });
-        let x = tt.dot(&block.dot(&t));
-        blocks
-            .slice_mut(s![n, ..])
-            .indexed_iter_mut()
-            .for_each(|(i, v)| {
-                *v = (x[[i / 8, i % 8]].round() as i64).clamp(-128, 127);
-            })
+        codesize[c2] += 1;
+        while let Some(next) = others[c2] {
+            c2 = next;
+            codesize[c2] += 1;
+        }
+    }
+
+    let mut bits = vec![0usize; codesize.iter().max().unwrap() + 1];
+    for &size in codesize.iter().filter(|&&size| size > 0) {
+        bits[size] += 1;
+    }
+
+    for i in (17..bits.len()).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
     }
+    bits.resize(17, 0);
+
+    // Remove the reserved symbol, which holds one of the longest codes.
+    let longest = (1..=16).rev().find(|&i| bits[i] > 0).unwrap();
+    bits[longest] -= 1;
+
+    let mut values = Vec::new();
+    for size in 1..=*codesize.iter().max().unwrap() {
+        for (symbol, _) in codesize[..256]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &s)| s == size)
+        {
+            values.push(symbol as u8);
+        }
+    }
+
+    let mut spec = HuffmanSpec {
+        bits: [0; 16],
+        values,
+    };
+    for (i, &count) in bits[1..].iter().enumerate() {
+        spec.bits[i] = count as u8;
+    }
+
+    spec
+}
+
+/// Symbol frequencies gathered from a first encoding pass.
+struct SymbolCounts {
+    dc: [u64; 256],
+    ac: [u64; 256],
+}
+
+impl SymbolCounts {
+    fn new() -> Self {
+        SymbolCounts {
+            dc: [0; 256],
+            ac: [0; 256],
+        }
+    }
+
+    /// Count one symbol.
+    fn count(&mut self, symbol: &Symbol) {
+        match *symbol {
+            Symbol::Dc(size, _) => self.dc[size as usize] += 1,
+            Symbol::Ac((run, size), _) => self.ac[((run << 4) | size) as usize] += 1,
+        }
+    }
+
+    fn add(&mut self, frame: &EncodedFrame) {
+        for (plane, skipped) in frame.planes().into_iter().zip(frame.skip_flags()) {
+            for (n, block) in plane.rows().into_iter().enumerate() {
+                if skipped.is_some_and(|flags| flags[n]) {
+                    continue;
+                }
+                for_each_symbol(block.insert_axis(Axis(0)), |symbol| self.count(&symbol));
+            }
+        }
+        if let Some(motion) = &frame.motion {
+            for_each_motion_symbol(motion.view(), |symbol| {
+                if let Symbol::Dc(size, _) = symbol {
+                    self.dc[size as usize] += 1;
+                }
+            });
+        }
+    }
+
+    /// Count every symbol of the built-in tables once, so that the optimal
+    /// tables can also code frames quantized differently from the counted ones.
+    fn cover_defaults(&mut self) {
+        for size in 0..=11 {
+            self.dc[size] += 1;
+        }
+        for run in 0..16 {
+            for size in 1..=10 {
+                self.ac[(run << 4) | size] += 1;
+            }
+        }
+        self.ac[((EOB.0 << 4) | EOB.1) as usize] += 1;
+        self.ac[((ZRL.0 << 4) | ZRL.1) as usize] += 1;
+    }
+
+    /// Optimal DC and AC table specs for the symbols counted so far.
+    fn specs(&self) -> (HuffmanSpec, HuffmanSpec) {
+        (
+            optimal_huffman_spec(&self.dc),
+            optimal_huffman_spec(&self.ac),
+        )
+    }
+}
+
+/// Pad a frame to the given size by replicating its last row and column.
+///
+/// Edge replication keeps the padding smooth, so it costs few bits and does
+/// not ring into the visible part of the edge blocks.
+fn pad_frame(frame: ArrayView3<u8>, height: usize, width: usize) -> Array3<u8> {
+    let (h, w, channels) = frame.dim();
+    Array3::from_shape_fn((height, width, channels), |(i, j, c)| {
+        frame[[i.min(h - 1), j.min(w - 1), c]]
+    })
+}
 
-    blocks += 128;
+/// Pad a plane to `height` x `width` by repeating its last row and column.
+fn pad_plane(plane: ArrayView2<u8>, height: usize, width: usize) -> Array2<u8> {
+    let (h, w) = plane.dim();
+    Array2::from_shape_fn((height, width), |(i, j)| {
+        plane[[i.min(h - 1), j.min(w - 1)]]
+    })
 }
 
 /// Reshapes a plane into an array of 8x8 blocks.
@@ -678,13 +1020,86 @@ fn reshape_into_plane(height: usize, width: usize, blocks: ArrayView2<i64>) -> A
     plane
 }
 
-// Quantization matrix
-const QUANTIZATION_TABLE: [i64; 64] = [
+/// Add residual blocks to a predicted plane in place, saturating to 0..=255.
+fn add_residual(mut plane: ArrayViewMut2<u8>, blocks: ArrayView2<i64>) {
+    let blocks_x = plane.ncols() / 8;
+
+    for (n, block) in blocks.rows().into_iter().enumerate() {
+        let (y, x) = (n / blocks_x * 8, n % blocks_x * 8);
+        for (i, &r) in block.iter().enumerate() {
+            let p = &mut plane[[y + i / 8, x + i % 8]];
+            *p = (*p as i64 + r).clamp(0, 255) as u8;
+        }
+    }
+}
+
+/// Flag the blocks whose sum of absolute differences from the co-located
+/// block of `reference` is at most `threshold`.
+fn unchanged_blocks(
+    blocks: ArrayView2<i64>,
+    reference: ArrayView2<u8>,
+    threshold: u32,
+) -> Vec<bool> {
+    let reference = reshape_into_blocks(reference);
+
+    blocks
+        .rows()
+        .into_iter()
+        .zip(reference.rows())
+        .map(|(block, previous)| {
+            let sad: u64 = block
+                .iter()
+                .zip(previous.iter())
+                .map(|(a, b)| a.abs_diff(*b))
+                .sum();
+            sad <= threshold as u64
+        })
+        .collect()
+}
+
+/// Copy the flagged blocks of `reference` into a plane in place.
+fn copy_blocks(mut plane: ArrayViewMut2<u8>, reference: ArrayView2<u8>, skipped: &[bool]) {
+    let blocks_x = plane.ncols() / 8;
+
+    for (n, _) in skipped.iter().enumerate().filter(|(_, &skip)| skip) {
+        let (y, x) = (n / blocks_x * 8, n % blocks_x * 8);
+        plane
+            .slice_mut(s![y..y + 8, x..x + 8])
+            .assign(&reference.slice(s![y..y + 8, x..x + 8]));
+    }
+}
+
+// Luma quantization matrix
+const LUMA_QUANTIZATION_TABLE: [i64; 64] = [
     16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
     14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
     92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
 ];
 
+// Chroma quantization matrix (ITU-T T.81 Annex K.2)
+const CHROMA_QUANTIZATION_TABLE: [i64; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Scale a quantization table by a quality factor from 1 to 100.
+///
+/// This follows the IJG (libjpeg) convention: quality 50 leaves the table
+/// unchanged, lower values scale the steps up by `5000 / quality` percent and
+/// higher values scale them down by `200 - 2 * quality` percent. Steps are
+/// clamped to 1..=255 so they still fit in a baseline 8-bit table.
+fn scale_quantization_table(table: &[i64; 64], quality: u8) -> [i64; 64] {
+    let quality = quality.clamp(1, 100) as i64;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+
+    table.map(|q| ((q * scale + 50) / 100).clamp(1, 255))
+}
+
 // Scan order matrix
 const SCAN_ORDER_TABLE: [usize; 64] = [
     0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
@@ -729,7 +1144,14 @@ fn unzigzag_order(mut input: ArrayViewMut2<i64>) {
 /// This is a lossless encoding step, used for the DC component of the DCT.
 /// The first element of the column is left unchanged, and each subsequent element
 /// is replaced by the difference between it and the previous element.
+///
+/// The predictor is reset at the start of every call, so callers pass one
+/// plane of one frame (or one restart interval of it) at a time.
 fn delta_encode(mut input: ArrayViewMut2<i64>) {
+    if input.is_empty() {
+        return;
+    }
+
     let mut prev = input[[0, 0]];
     for i in 1..input.len_of(Axis(0)) {
         let curr = input[[i, 0]];
@@ -738,70 +1160,42 @@ fn delta_encode(mut input: ArrayViewMut2<i64>) {
     }
 }
 
+/// Undo [`delta_encode`] on the first column of the input array in-place.
 fn delta_decode(mut input: ArrayViewMut2<i64>) {
     for i in 1..input.len_of(Axis(0)) {
         input[[i, 0]] += input[[i - 1, 0]];
     }
 }
 
-/// Convert an RGB image to YUV in-place.
-///
-/// This is a destructive conversion, so the input array will be modified.
-/// The conversion is done according to the standard RGB to YUV conversion
-/// formula, which is:
-///
-/// Y = 0.299R + 0.587G + 0.114B
-/// U = 0.565(B-Y) + 128
-/// V = 0.713(R-Y) + 128
+/// Apply `f` to the blocks that are not flagged as skipped.
 ///
-/// The resulting YUV values are stored in the input array, with the Y component
-/// in the first channel, the U component in the second channel, and the V
-/// component in the third channel.
-fn rgb_to_yuv(mut frame: ArrayViewMut3<u8>) {
-    let (height, width, _) = frame.dim();
-    for i in 0..height {
-        for j in 0..width {
-            let r = *frame.get((i, j, 0)).unwrap() as f64;
-            let g = *frame.get((i, j, 1)).unwrap() as f64;
-            let b = *frame.get((i, j, 2)).unwrap() as f64;
-            let y = 0.299 * r + 0.587 * g + 0.114 * b;
-            let u = 0.565 * (b - y) + 128.0;
-            let v = 0.713 * (r - y) + 128.0;
-            frame[[i, j, 0]] = y as u8;
-            frame[[i, j, 1]] = u as u8;
-            frame[[i, j, 2]] = v as u8;
-        }
+/// The coded blocks are gathered into a contiguous array first, so DC
+/// prediction runs from one coded block to the next as it does in the
+/// bitstream.
+fn with_coded_blocks<F>(mut blocks: ArrayViewMut2<i64>, skipped: Option<&[bool]>, f: F)
+where
+    F: FnOnce(ArrayViewMut2<i64>),
+{
+    let Some(skipped) = skipped else {
+        return f(blocks);
+    };
+
+    let coded: Vec<usize> = (0..skipped.len()).filter(|&n| !skipped[n]).collect();
+    let mut selected = blocks.select(Axis(0), &coded);
+    f(selected.view_mut());
+    for (&n, block) in coded.iter().zip(selected.rows()) {
+        blocks.row_mut(n).assign(&block);
     }
 }
 
-/// Convert a YUV image to RGB in-place.
-///
-/// This is a destructive conversion, so the input array will be modified.
-/// The conversion is done according to the standard YUV to RGB conversion
-/// formula, which is:
-///
-/// R = Y + 1.4903 * (V - 128)
-/// G = Y - 0.344 * (U - 128) - 0.714 * (V - 128)
-/// B = Y + 1.770 * (U - 128)
-///
-/// The resulting RGB values are stored in the input array, with the R component
-/// in the first channel, the G component in the second channel, and the B
-/// component in the third channel.
-fn yuv_to_rgb(mut frame: ArrayViewMut3<u8>) {
-    let (height, width, _) = frame.dim();
-    for i in 0..height {
-        for j in 0..width {
-            let y = *frame.get((i, j, 0)).unwrap() as f64;
-            let u = *frame.get((i, j, 1)).unwrap() as f64;
-            let v = *frame.get((i, j, 2)).unwrap() as f64;
-            let r = y + 1.4903 * (v - 128.0);
-            let g = y - 0.344 * (u - 128.0) - 0.714 * (v - 128.0);
-            let b = y + 1.770 * (u - 128.0);
-            frame[[i, j, 0]] = r as u8;
-            frame[[i, j, 1]] = g as u8;
-            frame[[i, j, 2]] = b as u8;
-        }
-    }
+/// Split optional per-block flags into the chunks matching the slices of a
+/// plane, yielding `None` for every slice if there are no flags.
+fn slice_flags(skipped: Option<&[bool]>, size: usize) -> impl Iterator<Item = Option<&[bool]>> {
+    let mut chunks = skipped.map(|flags| flags.chunks(size));
+    iter::from_fn(move || match &mut chunks {
+        Some(chunks) => chunks.next().map(Some),
+        None => Some(None),
+    })
 }
 
 #[derive(Debug, Clone)]
@@ -809,89 +1203,257 @@ struct EncodedFrame {
     y: Array2<i64>,
     u: Array2<i64>,
     v: Array2<i64>,
+    /// Macroblock motion vectors of a P-frame, whose blocks hold residuals.
+    motion: Option<Array3<i64>>,
+    /// Per-block skip flags of each plane of a conditionally replenished
+    /// P-frame. The coefficients of skipped blocks are never coded.
+    skipped: Option<[Vec<bool>; 3]>,
+    /// Quality factor of a rate controlled frame, see [`plane_params`].
+    quality: Option<u8>,
+}
+
+impl EncodedFrame {
+    /// The coefficient blocks of each plane in coding order.
+    fn planes(&self) -> [&Array2<i64>; 3] {
+        [&self.y, &self.u, &self.v]
+    }
+
+    /// The skip flags of each plane, if the frame has any.
+    fn skip_flags(&self) -> [Option<&[bool]>; 3] {
+        std::array::from_fn(|i| self.skipped.as_ref().map(|flags| flags[i].as_slice()))
+    }
 }
 
 const ZRL: (i64, i64) = (15, 0);
 const EOB: (i64, i64) = (0, 0);
 
-/// Encode the given frame using Huffman coding.
+/// A run-length coded symbol together with its magnitude bits.
+///
+/// DC symbols carry the coefficient size and AC symbols the `(run, size)`
+/// pair. The second field holds the `size` low bits of the coefficient, with
+/// negative values stored in one's complement as in JPEG.
+#[derive(Debug)]
+enum Symbol {
+    Dc(i64, i64),
+    Ac((i64, i64), i64),
+}
+
+/// Split a coefficient into its size category and magnitude bits.
+fn magnitude(v: i64) -> (i64, i64) {
+    let size = 64 - v.abs().leading_zeros() as i64;
+    let bits = if v < 0 {
+        (v - 1) & ((1 << (size)) - 1)
+    } else {
+        v
+    };
+    (size, bits)
+}
+
+/// Visit the symbols of the given blocks in coding order.
+///
+/// The symbols are produced as follows:
+///
+/// 1. The first coefficient of each block is emitted as a DC symbol.
+/// 2. Each nonzero AC coefficient is emitted as an AC symbol combining the
+///    length of the preceding run of zeros with the coefficient size.
+/// 3. Runs of more than 15 zeros are split with ZRL symbols.
+/// 4. If the block ends with a run of zeros, an EOB (End Of Block) symbol
+///    is emitted to indicate the end of the block.
+fn for_each_symbol<F>(blocks: ArrayView2<i64>, mut f: F)
+where
+    F: FnMut(Symbol),
+{
+    for block in blocks.rows() {
+        let mut run = 0;
+        let (size, v) = magnitude(block[0]);
+        f(Symbol::Dc(size, v));
+
+        for i in 1..64 {
+            if block[i] == 0 {
+                run += 1;
+            } else {
+                while run > 15 {
+                    f(Symbol::Ac(ZRL, 0));
+                    run -= 16;
+                }
+                let (size, v) = magnitude(block[i]);
+                f(Symbol::Ac((run, size), v));
+                run = 0;
+            }
+        }
+
+        if run > 0 {
+            f(Symbol::Ac(EOB, 0));
+        }
+    }
+}
+
+/// Visit the symbols of a motion vector field in coding order.
+///
+/// Macroblocks are visited in raster order, and the vertical then the
+/// horizontal component of each vector is emitted as a DC symbol holding the
+/// difference from the previous vector of the frame.
+fn for_each_motion_symbol<F>(motion: ArrayView3<i64>, mut f: F)
+where
+    F: FnMut(Symbol),
+{
+    let mut prev = [0, 0];
+    for vector in motion.lanes(Axis(2)) {
+        for (p, &v) in prev.iter_mut().zip(vector.iter()) {
+            let (size, bits) = magnitude(v - *p);
+            f(Symbol::Dc(size, bits));
+            *p = v;
+        }
+    }
+}
+
+/// Write one symbol with the DC or AC codebook, followed by its magnitude
+/// bits. Fails if the codebook has no code for the symbol.
+fn write_symbol<W>(
+    writer: &mut BitWriter<W, BigEndian>,
+    codebook: &HuffmanTable,
+    symbol: Symbol,
+) -> Result<()>
+where
+    W: Write,
+{
+    if codebook.symbol_bits(&symbol).is_none() {
+        return Err(anyhow!("Huffman table has no code for {:?}", symbol));
+    }
+
+    let (size, v) = match symbol {
+        Symbol::Dc(size, v) => {
+            writer.write_huffman(&codebook.dc_write, size)?;
+            (size, v)
+        }
+        Symbol::Ac(symbol, v) => {
+            writer.write_huffman(&codebook.ac_write, symbol)?;
+            (symbol.1, v)
+        }
+    };
+
+    if size > 0 {
+        writer.write(size as u32, v)?;
+    }
+
+    Ok(())
+}
+
+/// Write the symbols that `visit` passes to its callback with
+/// [`write_symbol`], stopping at the first one that cannot be written.
+fn write_symbols<W, V>(
+    writer: &mut BitWriter<W, BigEndian>,
+    codebook: &HuffmanTable,
+    visit: V,
+) -> Result<()>
+where
+    W: Write,
+    V: FnOnce(&mut dyn FnMut(Symbol)),
+{
+    let mut result = Ok(());
+    visit(&mut |symbol| {
+        if result.is_ok() {
+            result = write_symbol(writer, codebook, symbol);
+        }
+    });
+    result
+}
+
+/// Read the `size` magnitude bits of a coefficient and restore its sign.
+fn read_magnitude<R>(reader: &mut BitReader<R, BigEndian>, size: i64) -> io::Result<i64>
+where
+    R: Read,
+{
+    if size > 0 {
+        let v: i64 = reader.read(size as u32)?;
+        if v >= (1 << (size - 1)) {
+            Ok(v)
+        } else {
+            Ok(v - (1 << size) + 1)
+        }
+    } else {
+        Ok(0)
+    }
+}
+
+/// Encode the given blocks using Huffman coding.
 ///
 /// # Arguments
 ///
-/// * `frame` - Frame to encode.
-/// * `writer` - Writer to write the encoded frame to.
+/// * `blocks` - Blocks to encode, one plane or one slice of a plane.
+/// * `writer` - Writer to write the encoded blocks to.
 /// * `codebook` - Huffman codebook to use for encoding.
 ///
 /// # Notes
 ///
-/// This function assumes that the given `frame` has already been transformed
-/// into the frequency domain using the DCT and that the quantized coefficients
-/// are stored in the `y`, `u`, and `v` fields of the `frame`.
-///
-/// The encoding process is as follows:
+/// This function assumes that the given `blocks` have already been transformed
+/// into the frequency domain using the DCT, quantized and zigzagged.
 ///
-/// 1. For each plane (Y, U, V), the first coefficient is encoded using the
-///    DC codebook.
-/// 2. The remaining coefficients are encoded using the AC codebook.
-/// 3. The length of each run of zeros is encoded using the AC codebook.
-/// 4. The coefficient value is encoded using the AC codebook.
-/// 5. If the last run of zeros is not 15, an EOB (End Of Block) code is
-///    written to indicate the end of the block.
+/// Each symbol from [`for_each_symbol`] is written with the DC or AC codebook,
+/// followed by its magnitude bits. Given `skipped` flags, every block starts
+/// with a single bit that is set if the block is skipped, in which case none
+/// of its symbols follow.
 fn entropy_encode<W>(
-    frame: &EncodedFrame,
+    blocks: ArrayView2<i64>,
+    skipped: Option<&[bool]>,
     writer: &mut BitWriter<W, BigEndian>,
     codebook: &HuffmanTable,
-) where
+) -> Result<()>
+where
     W: Write,
 {
-    for plane in [&frame.y, &frame.u, &frame.v] {
-        for n in 0..plane.len_of(Axis(0)) {
-            let mut run = 0;
-            let v = plane[[n, 0]];
-            let size = 64 - v.abs().leading_zeros() as i64;
-            let v = if v < 0 {
-                (v - 1) & ((1 << (size)) - 1)
-            } else {
-                v
-            };
-
-            writer.write_huffman(&codebook.dc_write, size).unwrap();
-
-            if size > 0 {
-                writer.write(size as u32, v).unwrap();
-            }
+    let Some(skipped) = skipped else {
+        return write_symbols(writer, codebook, |f| for_each_symbol(blocks, f));
+    };
+
+    for (block, &skip) in blocks.rows().into_iter().zip(skipped) {
+        writer.write_bit(skip)?;
+        if !skip {
+            write_symbols(writer, codebook, |f| {
+                for_each_symbol(block.insert_axis(Axis(0)), f)
+            })?;
+        }
+    }
 
-            for i in 1..64 {
-                let v = plane[[n, i]];
-                let size = 64 - v.abs().leading_zeros() as i64;
-                let v = if v < 0 {
-                    (v - 1) & ((1 << (size)) - 1)
-                } else {
-                    v
-                };
+    Ok(())
+}
 
-                if plane[[n, i]] == 0 {
-                    run += 1;
-                } else {
-                    while run > 15 {
-                        writer.write_huffman(&codebook.ac_write, ZRL).unwrap();
-                        run -= 16;
-                    }
-                    writer
-                        .write_huffman(&codebook.ac_write, (run, size))
-                        .unwrap();
-                    if size > 0 {
-                        writer.write(size as u32, v).unwrap();
-                    }
-                    run = 0;
-                }
-            }
+/// Encode a motion vector field using the DC codebook.
+fn motion_encode<W>(
+    motion: ArrayView3<i64>,
+    writer: &mut BitWriter<W, BigEndian>,
+    codebook: &HuffmanTable,
+) -> Result<()>
+where
+    W: Write,
+{
+    write_symbols(writer, codebook, |f| for_each_motion_symbol(motion, f))
+}
 
-            if run > 0 {
-                writer.write_huffman(&codebook.ac_write, EOB).unwrap();
+/// Decode a `rows` x `cols` motion vector field written by [`motion_encode`].
+fn motion_decode<R>(
+    reader: &mut BitReader<R, BigEndian>,
+    codebook: &HuffmanTable,
+    rows: usize,
+    cols: usize,
+) -> Result<Array3<i64>>
+where
+    R: Read,
+{
+    let mut motion = Array3::zeros((rows, cols, 2));
+    let mut prev = [0, 0];
+    for mut vector in motion.lanes_mut(Axis(2)) {
+        for (p, v) in prev.iter_mut().zip(vector.iter_mut()) {
+            let size = reader.read_huffman(&codebook.dc_read)?;
+            if !(0..=11).contains(&size) {
+                return Err(anyhow!("Invalid motion vector code"));
             }
+            *p += read_magnitude(reader, size)?;
+            *v = *p;
         }
     }
+
+    Ok(motion)
 }
 
 /// Decodes the given reader using the given Huffman codebook and returns
@@ -908,11 +1470,13 @@ fn entropy_encode<W>(
 ///    written to indicate the end of the block.
 ///
 /// The output is an array of `num_blocks` blocks of 64 coefficients each.
+/// Codes outside the built-in tables, such as the filler codes of a table,
+/// and runs past the end of a block are rejected.
 fn entropy_decode<R>(
     reader: &mut BitReader<R, BigEndian>,
     codebook: &HuffmanTable,
     num_blocks: usize,
-) -> Array2<i64>
+) -> Result<Array2<i64>>
 where
     R: Read,
 {
@@ -920,213 +1484,1803 @@ where
 
     for n in 0..num_blocks {
         let mut position = 0;
-        let size = reader.read_huffman(&codebook.dc_read).unwrap();
-
-        let v = if size > 0 {
-            let v: i64 = reader.read(size as u32).unwrap();
-            if v >= (1 << (size - 1)) {
-                v
-            } else {
-                v - (1 << size) + 1
-            }
-        } else {
-            0
-        };
+        let size = reader.read_huffman(&codebook.dc_read)?;
+        if !(0..=11).contains(&size) {
+            return Err(anyhow!("Invalid DC code in frame"));
+        }
 
-        result[[n, position]] = v;
+        result[[n, position]] = read_magnitude(reader, size)?;
         position += 1;
 
         'inner: while position < 64 {
-            let (run, size) = reader.read_huffman(&codebook.ac_read).unwrap();
+            let (run, size) = reader.read_huffman(&codebook.ac_read)?;
 
             if run == 0 && size == 0 {
                 break 'inner;
             }
-
-            let v = if size > 0 {
-                let v: i64 = reader.read(size as u32).unwrap();
-                if v >= (1 << (size - 1)) {
-                    v
-                } else {
-                    v - (1 << size) + 1
-                }
-            } else {
-                0
-            };
+            if run < 0 || size > 10 || (size == 0 && run != 15) {
+                return Err(anyhow!("Invalid AC code in frame"));
+            }
 
             position += run as usize;
-            result[[n, position]] = v;
+            if position >= 64 {
+                return Err(anyhow!("AC run past the end of a block"));
+            }
+            result[[n, position]] = read_magnitude(reader, size)?;
             position += 1;
         }
     }
 
-    result
+    Ok(result)
 }
 
-/// Encode a single frame.
+/// Reconstructed Y, U and V planes of a frame at their padded, coded sizes.
 ///
-/// This function takes a mutable reference to an image represented as an RGB array of
-/// u8 values and returns an `EncodedFrame` containing the Y, U, and V components of the image
-/// after they have been DCT'd, quantized, zigzagged, and delta encoded.
-///
-/// This function does not return an error. It is the caller's responsibility to ensure that the
-/// array is a valid image with a power of two width and height, and that it is large enough to
-/// fit into memory.
-fn encode_frame(mut frame: Array3<u8>) -> EncodedFrame {
-    rgb_to_yuv(frame.view_mut());
-
-    let (h, w, _) = frame.dim();
-    let y = frame.slice(s![0..h, 0..w, 0]);
-    let u = frame.slice(s![0..h;2, 0..w;2, 1]);
-    let v = frame.slice(s![0..h;2, 0..w;2, 2]);
-
-    let mut yblocks = reshape_into_blocks(y);
-    fdct(yblocks.view_mut());
-    zigzag_order(yblocks.view_mut());
-    // delta_encode(yblocks.view_mut());
-
-    let mut ublocks = reshape_into_blocks(u);
-    fdct(ublocks.view_mut());
-    zigzag_order(ublocks.view_mut());
-    // delta_encode(ublocks.view_mut());
-
-    let mut vblocks = reshape_into_blocks(v);
-    fdct(vblocks.view_mut());
-    zigzag_order(vblocks.view_mut());
-    // delta_encode(vblocks.view_mut());
-
-    EncodedFrame {
-        y: yblocks,
-        u: ublocks,
-        v: vblocks,
-    }
+/// The last reconstructed frame is the reference that P-frames are predicted
+/// from, so the encoder keeps one in step with the decoder.
+#[derive(Debug, Clone)]
+struct Planes {
+    y: Array2<u8>,
+    u: Array2<u8>,
+    v: Array2<u8>,
 }
 
-fn decode_frame<R>(
-    reader: &mut BitReader<R, BigEndian>,
-    codebook: &HuffmanTable,
-    height: usize,
-    width: usize,
-) -> Frame
-where
-    R: Read,
-{
-    let hblocks = height / 8;
-    let wblocks = width / 8;
+/// A frame read by `encode`.
+enum Picture {
+    /// Packed RGB, as decoded by ffmpeg or read from a raw file.
+    Rgb(Frame),
+    /// YUV planes of the frame size in the given chroma layout, as read from
+    /// a Y4M or raw file.
+    Yuv(Planes, Chroma),
+}
 
-    let mut yblocks = entropy_decode(reader, codebook, hblocks * wblocks);
-    let mut ublocks = entropy_decode(reader, codebook, hblocks * wblocks / 4);
-    let mut vblocks = entropy_decode(reader, codebook, hblocks * wblocks / 4);
+impl Planes {
+    fn planes(&self) -> [&Array2<u8>; 3] {
+        [&self.y, &self.u, &self.v]
+    }
 
-    // delta_decode(yblocks.view_mut());
-    unzigzag_order(yblocks.view_mut());
-    idct(yblocks.view_mut());
+    /// Copy the blocks flagged in `skipped` from `reference` into every plane.
+    fn replenish(&mut self, reference: &Planes, skipped: &[Vec<bool>; 3]) {
+        copy_blocks(self.y.view_mut(), reference.y.view(), &skipped[0]);
+        copy_blocks(self.u.view_mut(), reference.u.view(), &skipped[1]);
+        copy_blocks(self.v.view_mut(), reference.v.view(), &skipped[2]);
+    }
 
-    // delta_decode(ublocks.view_mut());
-    unzigzag_order(ublocks.view_mut());
-    idct(ublocks.view_mut());
+    /// Motion compensated prediction of every plane from this reference.
+    fn predict(&self, motion: ArrayView3<i64>, header: &Header) -> Planes {
+        let factors = header.chroma.subsampling();
+        Planes {
+            y: compensate(self.y.view(), motion, (1, 1)),
+            u: compensate(self.u.view(), motion, factors),
+            v: compensate(self.v.view(), motion, factors),
+        }
+    }
+}
 
-    // delta_decode(vblocks.view_mut());
-    unzigzag_order(vblocks.view_mut());
-    idct(vblocks.view_mut());
+/// Blocks per slice and quantization table of the Y, U and V planes.
+///
+/// The tables are the stream's, scaled by the frame's `quality` if it has one.
+fn plane_params(header: &Header, quality: Option<u8>) -> [(usize, [i64; 64]); 3] {
+    let (luma, chroma) = header.slice_blocks();
+    let scale = |table: &[i64; 64]| match quality {
+        Some(quality) => scale_quantization_table(table, quality),
+        None => *table,
+    };
+    let (luma_table, chroma_table) = (scale(&header.luma_table), scale(&header.chroma_table));
+    [
+        (luma.max(1), luma_table),
+        (chroma.max(1), chroma_table),
+        (chroma.max(1), chroma_table),
+    ]
+}
+
+/// Bring a picture to the padded size and chroma layout of the stream.
+///
+/// RGB frames are converted to YUV and their chroma planes reduced with
+/// `filter`. YUV planes already in the stream's layout are only padded, and
+/// others are expanded to full resolution by replication and then reduced
+/// like RGB frames, which leaves them unchanged if the layouts agree.
+fn picture_planes(picture: Picture, header: &Header, filter: Downsample) -> Planes {
+    let (h, w) = header.padded_size();
+    let (ch, cw) = header.chroma_size();
+
+    match picture {
+        Picture::Rgb(mut frame) => {
+            rgb_to_yuv(frame.view_mut(), header.matrix, header.range);
+            let frame = if frame.dim() == (h, w, 3) {
+                frame
+            } else {
+                pad_frame(frame.view(), h, w)
+            };
+
+            Planes {
+                y: frame.slice(s![0..h, 0..w, 0]).to_owned(),
+                u: downsample(frame.slice(s![0..h, 0..w, 1]), ch, cw, filter),
+                v: downsample(frame.slice(s![0..h, 0..w, 2]), ch, cw, filter),
+            }
+        }
+        Picture::Yuv(planes, chroma) => {
+            let resample = |plane: &Array2<u8>| {
+                if header.chroma == Chroma::Gray {
+                    Array2::zeros((0, 0))
+                } else if chroma == header.chroma {
+                    pad_plane(plane.view(), ch, cw)
+                } else if chroma == Chroma::Gray {
+                    Array2::from_elem((ch, cw), 128)
+                } else {
+                    let factors = chroma.subsampling();
+                    let full = upsample(
+                        plane.view(),
+                        factors,
+                        header.height,
+                        header.width,
+                        Upsample::Replicate,
+                    );
+                    downsample(pad_plane(full.view(), h, w).view(), ch, cw, filter)
+                }
+            };
+
+            Planes {
+                y: pad_plane(planes.y.view(), h, w),
+                u: resample(&planes.u),
+                v: resample(&planes.v),
+            }
+        }
+    }
+}
+
+/// Prepare a single frame for the transform.
+///
+/// This function takes an image represented as an RGB array of u8 values and returns an
+/// `EncodedFrame` whose Y, U and V blocks still hold samples, or residuals in a P-frame, for
+/// [`quantize_frame`] to code.
+///
+/// Frames of any size are accepted; they are padded to a whole number of MCUs by edge
+/// replication before the transform, and the decoder crops the padding off again. The chroma
+/// planes are reduced to the header's layout with the given `filter`.
+///
+/// Given a `reference`, the frame is coded as a P-frame: each macroblock is matched against
+/// the reference luma plane and only the difference from the motion compensated prediction is
+/// transformed. If the header enables skip blocks, blocks within `skip_threshold` of the
+/// reference are flagged as skipped instead and the rest are coded as in an I-frame.
+fn predict_frame(
+    picture: Picture,
+    header: &Header,
+    filter: Downsample,
+    reference: Option<&Planes>,
+    skip_threshold: u32,
+) -> EncodedFrame {
+    let Planes { y, u, v } = picture_planes(picture, header, filter);
+    let y = y.view();
+
+    let mut planes = [
+        reshape_into_blocks(y),
+        reshape_into_blocks(u.view()),
+        reshape_into_blocks(v.view()),
+    ];
+
+    let mut motion = None;
+    let mut skipped = None;
+    match reference {
+        Some(reference) if header.skip_blocks => {
+            let previous = reference.planes();
+            skipped = Some(std::array::from_fn(|i| {
+                unchanged_blocks(planes[i].view(), previous[i].view(), skip_threshold)
+            }));
+        }
+        Some(reference) => {
+            let vectors = estimate(y, reference.y.view());
+            let prediction = reference.predict(vectors.view(), header);
+            for (blocks, predicted) in planes.iter_mut().zip(prediction.planes()) {
+                *blocks -= &reshape_into_blocks(predicted.view());
+            }
+            motion = Some(vectors);
+        }
+        None => {}
+    }
+
+    let [y, u, v] = planes;
+    EncodedFrame {
+        y,
+        u,
+        v,
+        motion,
+        skipped,
+        quality: None,
+    }
+}
+
+/// Largest magnitude of a quantized residual coefficient.
+///
+/// Residuals span twice the range of samples, so at fine quantizer steps their
+/// coefficients can reach AC size 11 and, after DC prediction, DC size 12, one
+/// more than the built-in tables can code. Limiting them to size 10 keeps the
+/// AC coefficients and any DC difference within the tables.
+const RESIDUAL_LIMIT: i64 = (1 << 10) - 1;
+
+/// Transform, quantize, zigzag and (if the header enables DC prediction) delta encode the blocks
+/// of a frame from [`predict_frame`].
+///
+/// The quantization tables are taken from the stream `header`, scaled by `quality` if given.
+/// Residual coefficients are clamped to [`RESIDUAL_LIMIT`]. Given a lambda and codebook in
+/// `rdo`, the coefficients are rate-distortion optimized. With a restart interval the DC
+/// predictor starts afresh in every slice.
+fn quantize_frame(
+    mut frame: EncodedFrame,
+    header: &Header,
+    quality: Option<u8>,
+    rdo: Option<(f64, &HuffmanTable)>,
+) -> EncodedFrame {
+    let (transform, quotients): (Transform, Quotients) = match frame.motion {
+        Some(_) => (fdct_residual, fdct_residual_quotients),
+        None => (fdct, fdct_quotients),
+    };
+
+    let skipped = frame.skipped.take();
+    let mut planes = [frame.y, frame.u, frame.v];
+    let params = plane_params(header, quality);
+    for (i, (blocks, (size, table))) in planes.iter_mut().zip(params).enumerate() {
+        let flags = skipped.as_ref().map(|flags| flags[i].as_slice());
+        let exact = rdo.map(|_| quotients(blocks.view(), &table));
+        transform(blocks.view_mut(), &table);
+        zigzag_order(blocks.view_mut());
+        if frame.motion.is_some() {
+            blocks.mapv_inplace(|c| c.clamp(-RESIDUAL_LIMIT, RESIDUAL_LIMIT));
+        }
+        if let Some(((lambda, codebook), exact)) = rdo.zip(exact) {
+            rdo::optimize(blocks.view_mut(), exact.view(), &table, lambda, codebook);
+        }
+
+        if header.dc_prediction {
+            for (slice, flags) in blocks
+                .axis_chunks_iter_mut(Axis(0), size)
+                .zip(slice_flags(flags, size))
+            {
+                with_coded_blocks(slice, flags, delta_encode);
+            }
+        }
+    }
+
+    let [y, u, v] = planes;
+    EncodedFrame {
+        y,
+        u,
+        v,
+        motion: frame.motion,
+        skipped,
+        quality,
+    }
+}
+
+/// Keep the first `coefficients` coefficients in zigzag order of every block
+/// of a quantized frame and zero the higher frequencies.
+fn truncate_frame(mut frame: EncodedFrame, coefficients: usize) -> EncodedFrame {
+    for blocks in [&mut frame.y, &mut frame.u, &mut frame.v] {
+        blocks.slice_mut(s![.., coefficients..]).fill(0);
+    }
+    frame
+}
+
+/// Reconstruct an encoded frame exactly as the decoder will, so that it can
+/// serve as the reference of the next frame.
+fn reconstruct(frame: &EncodedFrame, header: &Header, reference: Option<&Planes>) -> Planes {
+    let transform: Transform = match frame.motion {
+        Some(_) => idct_residual,
+        None => idct,
+    };
+
+    let mut planes = frame.planes().map(Array2::clone);
+    for ((blocks, (size, table)), flags) in planes
+        .iter_mut()
+        .zip(plane_params(header, frame.quality))
+        .zip(frame.skip_flags())
+    {
+        for (slice, flags) in blocks
+            .axis_chunks_iter_mut(Axis(0), size)
+            .zip(slice_flags(flags, size))
+        {
+            with_coded_blocks(slice, flags, |blocks| {
+                reconstruct_blocks(blocks, &table, header.dc_prediction, transform)
+            });
+        }
+    }
+
+    let prediction = frame
+        .motion
+        .as_ref()
+        .zip(reference)
+        .map(|(motion, reference)| reference.predict(motion.view(), header));
+    let mut planes = compose_planes(planes, header, prediction);
+    if let Some((skipped, reference)) = frame.skipped.as_ref().zip(reference) {
+        planes.replenish(reference, skipped);
+    }
+    planes
+}
 
-    let y = reshape_into_plane(height, width, yblocks.view());
-    let u = reshape_into_plane(height / 2, width / 2, ublocks.view());
-    let v = reshape_into_plane(height / 2, width / 2, vblocks.view());
+/// Encode the frames of one group of pictures.
+///
+/// The first frame is coded as an I-frame and every later one as a P-frame
+/// predicted from the reconstruction of the frame before it.
+///
+/// Given a rate controller, every frame is quantized with the highest quality
+/// whose payload, entropy coded with `codebook`, meets the controller's
+/// target, and the size of that payload is committed to its buffer. Given a
+/// `lambda`, the quantized coefficients are rate-distortion optimized for
+/// `codebook`.
+fn encode_gop(
+    frames: Vec<Picture>,
+    header: &Header,
+    filter: Downsample,
+    skip_threshold: u32,
+    codebook: &HuffmanTable,
+    rate: Option<&Mutex<RateControl>>,
+    lambda: Option<f64>,
+) -> Result<Vec<EncodedFrame>> {
+    let rdo = lambda.map(|lambda| (lambda, codebook));
+    let mut reference = None;
+    let mut encoded = Vec::with_capacity(frames.len());
+    let last = frames.len().saturating_sub(1);
+
+    for (i, frame) in frames.into_iter().enumerate() {
+        let predicted = predict_frame(frame, header, filter, reference.as_ref(), skip_threshold);
+        let frame = match rate {
+            Some(rate) => {
+                let (target, strict) = {
+                    let rate = rate.lock().unwrap();
+                    (rate.target(), rate.is_strict())
+                };
+                let size = |frame: &EncodedFrame| {
+                    Ok(8 * frame_payload(frame, header, codebook)?.len() as u64)
+                };
+                let quantize =
+                    |quality| quantize_frame(predicted.clone(), header, Some(quality as u8), rdo);
+
+                let quality = search(1, 100, target, |quality| size(&quantize(quality)))?;
+                let mut frame = quantize(quality.map_or(1, |(quality, _)| quality));
+                let mut bits = match quality {
+                    Some((_, bits)) => bits,
+                    None => size(&frame)?,
+                };
 
-    let mut frame = Array3::<u8>::zeros((height, width, 3));
+                if bits > target && strict {
+                    let truncated =
+                        search(1, 64, target, |n| size(&truncate_frame(frame.clone(), n)))?;
+                    let (coefficients, truncated) = truncated.ok_or_else(|| {
+                        anyhow!("Frame does not fit in {} bytes with DC only", target / 8)
+                    })?;
+                    frame = truncate_frame(frame, coefficients);
+                    bits = truncated;
+                }
 
-    frame.indexed_iter_mut().for_each(|((i, j, c), elem)| {
-        *elem = match c {
-            0 => y[[i, j]],
-            1 => u[[i >> 1, j >> 1]],
-            _ => v[[i >> 1, j >> 1]],
+                rate.lock().unwrap().commit(bits);
+                frame
+            }
+            None => quantize_frame(predicted, header, None, rdo),
         };
+
+        if i < last {
+            reference = Some(reconstruct(&frame, header, reference.as_ref()));
+        }
+        encoded.push(frame);
+    }
+
+    Ok(encoded)
+}
+
+/// Entropy code an encoded frame into the payload of one frame.
+///
+/// The payload starts with the quality factor of rate controlled frames.
+/// Without a restart interval the motion vectors and the planes are coded
+/// back to back as a single bitstream. Otherwise each of them and each slice
+/// of a plane is coded on its own, and they are joined behind an offset
+/// table.
+fn frame_payload(
+    frame: &EncodedFrame,
+    header: &Header,
+    codebook: &HuffmanTable,
+) -> Result<Vec<u8>> {
+    let mut data: Vec<u8> = frame.quality.into_iter().collect();
+
+    if header.restart_interval.is_none() {
+        let mut bits = BitWriter::endian(data, BigEndian);
+        if let Some(motion) = &frame.motion {
+            motion_encode(motion.view(), &mut bits, codebook)?;
+        }
+        for (plane, skipped) in frame.planes().into_iter().zip(frame.skip_flags()) {
+            entropy_encode(plane.view(), skipped, &mut bits, codebook)?;
+        }
+        bits.byte_align()?;
+        return Ok(bits.into_writer());
+    }
+
+    let mut slices = Vec::new();
+    if let Some(motion) = &frame.motion {
+        let mut bits = BitWriter::endian(Vec::new(), BigEndian);
+        motion_encode(motion.view(), &mut bits, codebook)?;
+        bits.byte_align()?;
+        slices.push(bits.into_writer());
+    }
+    for ((plane, (size, _)), skipped) in frame
+        .planes()
+        .into_iter()
+        .zip(plane_params(header, frame.quality))
+        .zip(frame.skip_flags())
+    {
+        for (slice, skipped) in plane
+            .axis_chunks_iter(Axis(0), size)
+            .zip(slice_flags(skipped, size))
+        {
+            let mut bits = BitWriter::endian(Vec::new(), BigEndian);
+            entropy_encode(slice, skipped, &mut bits, codebook)?;
+            bits.byte_align()?;
+            slices.push(bits.into_writer());
+        }
+    }
+
+    data.extend(join_slices(&slices)?);
+    Ok(data)
+}
+
+/// Undo DC prediction, zigzag ordering, quantization and the DCT of a run of
+/// blocks in place.
+fn reconstruct_blocks(
+    mut blocks: ArrayViewMut2<i64>,
+    table: &[i64; 64],
+    dc_prediction: bool,
+    transform: Transform,
+) {
+    if dc_prediction {
+        delta_decode(blocks.view_mut());
+    }
+
+    unzigzag_order(blocks.view_mut());
+    transform(blocks, table);
+}
+
+/// Entropy decode, dequantize and inverse transform one run of blocks in place.
+///
+/// Given `skipped` flags, the skip bit of every block is read into them and
+/// only the coded blocks are decoded.
+fn decode_blocks<R>(
+    reader: &mut BitReader<R, BigEndian>,
+    codebook: &HuffmanTable,
+    mut blocks: ArrayViewMut2<i64>,
+    mut skipped: Option<&mut [bool]>,
+    table: &[i64; 64],
+    dc_prediction: bool,
+    transform: Transform,
+) -> Result<()>
+where
+    R: Read,
+{
+    match skipped.as_deref_mut() {
+        Some(flags) => {
+            for (mut block, skip) in blocks.rows_mut().into_iter().zip(flags) {
+                *skip = reader.read_bit()?;
+                if !*skip {
+                    block.assign(&entropy_decode(reader, codebook, 1)?.row(0));
+                }
+            }
+        }
+        None => blocks.assign(&entropy_decode(reader, codebook, blocks.nrows())?),
+    }
+
+    with_coded_blocks(blocks, skipped.as_deref(), |blocks| {
+        reconstruct_blocks(blocks, table, dc_prediction, transform)
     });
 
-    yuv_to_rgb(frame.view_mut());
+    Ok(())
+}
+
+/// Turn inverse transformed blocks back into planes.
+///
+/// For a P-frame the blocks hold residuals, which are added to the motion
+/// compensated `prediction`.
+fn compose_planes(blocks: [Array2<i64>; 3], header: &Header, prediction: Option<Planes>) -> Planes {
+    let [y, u, v] = blocks;
+    match prediction {
+        Some(mut planes) => {
+            add_residual(planes.y.view_mut(), y.view());
+            add_residual(planes.u.view_mut(), u.view());
+            add_residual(planes.v.view_mut(), v.view());
+            planes
+        }
+        None => {
+            let (height, width) = header.padded_size();
+            let (ch, cw) = header.chroma_size();
+            Planes {
+                y: reshape_into_plane(height, width, y.view()),
+                u: reshape_into_plane(ch, cw, u.view()),
+                v: reshape_into_plane(ch, cw, v.view()),
+            }
+        }
+    }
+}
+
+/// Zeroed blocks of the Y, U and V planes of a frame.
+fn empty_blocks(header: &Header) -> [Array2<i64>; 3] {
+    let (height, width) = header.padded_size();
+    let (ch, cw) = header.chroma_size();
+    [
+        Array2::zeros(((height / 8) * (width / 8), 64)),
+        Array2::zeros(((ch / 8) * (cw / 8), 64)),
+        Array2::zeros(((ch / 8) * (cw / 8), 64)),
+    ]
+}
+
+/// Entropy decode the payload of an I-frame, without its quality factor, and
+/// undo DC prediction, leaving the quantized coefficients of every block in
+/// zigzag order.
+fn decode_coefficients(
+    data: &[u8],
+    codebook: &HuffmanTable,
+    header: &Header,
+) -> Result<[Array2<i64>; 3]> {
+    let mut planes = empty_blocks(header);
+    let mut slices = Vec::new();
+    for (blocks, (size, _)) in planes.iter_mut().zip(plane_params(header, None)) {
+        slices.extend(blocks.axis_chunks_iter_mut(Axis(0), size));
+    }
+
+    let decode =
+        |reader: &mut BitReader<&[u8], BigEndian>, mut slice: ArrayViewMut2<i64>| -> Result<()> {
+            slice.assign(&entropy_decode(reader, codebook, slice.nrows())?);
+            if header.dc_prediction {
+                delta_decode(slice);
+            }
+            Ok(())
+        };
+    if header.restart_interval.is_none() {
+        let mut reader = BitReader::endian(data, BigEndian);
+        for slice in slices {
+            decode(&mut reader, slice)?;
+        }
+    } else {
+        let data = split_slices(data, slices.len())?;
+        for (slice, data) in slices.into_iter().zip(data) {
+            decode(&mut BitReader::endian(data, BigEndian), slice)?;
+        }
+    }
+
+    Ok(planes)
+}
+
+/// Requantize decoded planes as an I-frame with the tables of a frame of the
+/// given `quality`, leaving the coefficients of every block in zigzag order.
+fn requantize(planes: &Planes, header: &Header, quality: Option<u8>) -> [Array2<i64>; 3] {
+    let params = plane_params(header, quality);
+    let mut blocks = planes
+        .planes()
+        .map(|plane| reshape_into_blocks(plane.view()));
+    for (blocks, (_, table)) in blocks.iter_mut().zip(params) {
+        fdct(blocks.view_mut(), &table);
+        zigzag_order(blocks.view_mut());
+    }
+    blocks
+}
+
+/// Split the quality factor of a rate controlled frame off its payload.
+fn frame_quality<'a>(data: &'a [u8], header: &Header) -> Result<(Option<u8>, &'a [u8])> {
+    match data.split_first() {
+        Some((&quality, data)) if header.frame_quality => {
+            if !(1..=100).contains(&quality) {
+                return Err(anyhow!("Invalid frame quality: {}", quality));
+            }
+            Ok((Some(quality), data))
+        }
+        None if header.frame_quality => Err(anyhow!("Frame is missing its quality")),
+        _ => Ok((None, data)),
+    }
+}
+
+/// Decode the payload of one frame into its planes.
+///
+/// The frame is a P-frame predicted from `reference` if one is given, and an
+/// I-frame otherwise. Skipped blocks of a P-frame are copied from the
+/// reference and its other blocks are decoded like those of an I-frame.
+/// Slices are handed out to `threads` workers, which each entropy decode and
+/// inverse transform their blocks in place. Streams without a restart
+/// interval form a single bitstream and are decoded on the calling thread.
+fn decode_planes(
+    data: &[u8],
+    codebook: &HuffmanTable,
+    header: &Header,
+    reference: Option<&Planes>,
+    transform: Idct,
+    threads: usize,
+) -> Result<Planes> {
+    let (height, width) = header.padded_size();
+    let (rows, cols) = macroblocks(height, width);
+    let mut planes = empty_blocks(header);
+
+    let skip = reference.is_some() && header.skip_blocks;
+    let compensated = reference.is_some() && !header.skip_blocks;
+    let mut skipped = planes.each_ref().map(|blocks| vec![false; blocks.nrows()]);
+
+    let transform: Transform = match (compensated, transform) {
+        (true, _) => idct_residual,
+        (false, Idct::Float) => idct,
+        (false, Idct::Hw) => hw_idct,
+    };
+
+    let (quality, data) = frame_quality(data, header)?;
+    let params = plane_params(header, quality);
+
+    let mut motion = None;
+    if header.restart_interval.is_none() {
+        let mut reader = BitReader::endian(data, BigEndian);
+        if compensated {
+            motion = Some(motion_decode(&mut reader, codebook, rows, cols)?);
+        }
+        for ((blocks, (_, table)), flags) in planes.iter_mut().zip(&params).zip(skipped.iter_mut())
+        {
+            decode_blocks(
+                &mut reader,
+                codebook,
+                blocks.view_mut(),
+                skip.then_some(&mut flags[..]),
+                table,
+                header.dc_prediction,
+                transform,
+            )?;
+        }
+    } else {
+        let mut jobs = Vec::new();
+        for ((blocks, &(size, ref table)), flags) in
+            planes.iter_mut().zip(&params).zip(skipped.iter_mut())
+        {
+            for (slice, flags) in blocks
+                .axis_chunks_iter_mut(Axis(0), size)
+                .zip(flags.chunks_mut(size))
+            {
+                jobs.push((slice, skip.then_some(flags), table));
+            }
+        }
+
+        let mut slices = split_slices(data, compensated as usize + jobs.len())?;
+        if compensated {
+            let mut reader = BitReader::endian(slices.remove(0), BigEndian);
+            motion = Some(motion_decode(&mut reader, codebook, rows, cols)?);
+        }
+        let jobs = Mutex::new(jobs.into_iter().zip(slices));
+
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| -> Result<()> {
+                        loop {
+                            let job = jobs.lock().unwrap().next();
+                            let Some(((blocks, flags, table), data)) = job else {
+                                return Ok(());
+                            };
+                            decode_blocks(
+                                &mut BitReader::endian(data, BigEndian),
+                                codebook,
+                                blocks,
+                                flags,
+                                table,
+                                header.dc_prediction,
+                                transform,
+                            )?;
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().unwrap())
+        })?;
+    }
+
+    let prediction = reference
+        .zip(motion)
+        .map(|(reference, motion)| reference.predict(motion.view(), header));
+    let mut planes = compose_planes(planes, header, prediction);
+    if let Some(reference) = reference.filter(|_| skip) {
+        planes.replenish(reference, &skipped);
+    }
+    Ok(planes)
+}
+
+/// Copy decoded planes with the deblocking filter applied to every plane, at
+/// the strength of the DC step of its quantization table.
+fn deblock_planes(planes: &Planes, header: &Header, quality: Option<u8>) -> Planes {
+    let [(_, luma), (_, chroma), _] = plane_params(header, quality);
+    let mut planes = planes.clone();
+    deblock(planes.y.view_mut(), luma[0]);
+    deblock(planes.u.view_mut(), chroma[0]);
+    deblock(planes.v.view_mut(), chroma[0]);
+    planes
+}
+
+/// Crop decoded planes to the frame size, restore the chroma resolution with
+/// the given `filter` and convert to RGB.
+fn planes_to_frame(planes: &Planes, header: &Header, filter: Upsample) -> Frame {
+    let (h, w) = (header.height, header.width);
+    let mut frame = Array3::<u8>::from_elem((h, w, 3), 128);
+
+    frame
+        .slice_mut(s![.., .., 0])
+        .assign(&planes.y.slice(s![0..h, 0..w]));
+
+    if header.chroma != Chroma::Gray {
+        let factors = header.chroma.subsampling();
+        frame
+            .slice_mut(s![.., .., 1])
+            .assign(&upsample(planes.u.view(), factors, h, w, filter));
+        frame
+            .slice_mut(s![.., .., 2])
+            .assign(&upsample(planes.v.view(), factors, h, w, filter));
+    }
+
+    yuv_to_rgb(frame.view_mut(), header.matrix, header.range);
 
     frame
 }
 
-fn encode(infile: &str, outfile: &str) -> Result<()> {
-    let codebook = HuffmanTable::new()?;
+/// Group `frames` into GOPs of `gop` frames, the unit of parallel encoding.
+fn gops(
+    frames: impl Iterator<Item = Result<Picture>>,
+    gop: usize,
+) -> impl Iterator<Item = Result<Vec<Picture>>> {
+    let mut frames = frames.fuse();
+    iter::from_fn(move || {
+        let mut group = Vec::with_capacity(gop);
+        for frame in frames.by_ref() {
+            match frame {
+                Ok(frame) => group.push(frame),
+                Err(e) => return Some(Err(e)),
+            }
+            if group.len() == gop {
+                break;
+            }
+        }
+        (!group.is_empty()).then_some(Ok(group))
+    })
+}
+
+/// Run `work` over `jobs` on a pool of `threads` workers and hand each
+/// result to `consume` in order.
+///
+/// Jobs are pulled from the iterator on the calling thread and `consume`
+/// runs on a thread of its own, so decoding, encoding and writing overlap.
+/// Both queues are bounded to keep memory flat on long inputs.
+fn pipeline<J, T, W, C>(
+    jobs: impl Iterator<Item = Result<J>>,
+    threads: usize,
+    work: W,
+    mut consume: C,
+) -> Result<()>
+where
+    J: Send,
+    T: Send,
+    W: Fn(J) -> T + Sync,
+    C: FnMut(T) -> Result<()> + Send,
+{
+    let (job_tx, job_rx) = mpsc::sync_channel::<(usize, J)>(2 * threads);
+    let (result_tx, result_rx) = mpsc::sync_channel::<(usize, T)>(2 * threads);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            let work = &work;
+            scope.spawn(move || loop {
+                let job = job_rx.lock().unwrap().recv();
+                let Ok((index, job)) = job else { break };
+                if result_tx.send((index, work(job))).is_err() {
+                    break;
+                }
+            });
+        }
+        // Only the workers may hold the queue ends, so a failed writer or a
+        // finished input shuts the whole pipeline down.
+        drop(job_rx);
+        drop(result_tx);
+
+        let writer = scope.spawn(move || -> Result<()> {
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (index, result) in result_rx {
+                pending.insert(index, result);
+                while let Some(result) = pending.remove(&next) {
+                    consume(result)?;
+                    next += 1;
+                }
+            }
+            Ok(())
+        });
+
+        for (index, job) in jobs.enumerate() {
+            if job_tx.send((index, job?)).is_err() {
+                break;
+            }
+        }
+        drop(job_tx);
+
+        writer.join().unwrap()
+    })
+}
+
+/// The input of `encode`: its properties and an iterator over its frames.
+struct Input {
+    width: usize,
+    height: usize,
+    frame_rate: (u32, u32),
+    /// Estimated number of frames.
+    frame_count: usize,
+    /// Range of the planes of a Y4M file, if it has a tag for it.
+    range: Option<Range>,
+    pictures: Box<dyn Iterator<Item = Result<Picture>>>,
+}
+
+/// Open the input of `encode`: a raw file if `--raw` is given, otherwise a
+/// Y4M file or anything ffmpeg can decode.
+fn open_input(args: &EncodeArgs) -> Result<Input> {
+    let path = args.infile.as_str();
+    if let Some((width, height)) = args.raw {
+        let frame_size = args.pix_fmt.frame_size(width, height) as u64;
+        let file_size = fs::metadata(path)?.len();
+        if file_size % frame_size != 0 {
+            return Err(anyhow!(
+                "Raw file size {} is not a multiple of the {}x{} frame size {}",
+                file_size,
+                width,
+                height,
+                frame_size
+            ));
+        }
+
+        let frame_rate = frame_rate_to_rational(args.frame_rate);
+        if frame_rate.0 == 0 {
+            return Err(anyhow!("Invalid frame rate: {}", args.frame_rate));
+        }
+
+        let mut reader = RawReader::new(
+            BufReader::new(File::open(path)?),
+            width,
+            height,
+            args.pix_fmt,
+        );
+        return Ok(Input {
+            width,
+            height,
+            frame_rate,
+            frame_count: (file_size / frame_size) as usize,
+            range: None,
+            pictures: Box::new(iter::from_fn(move || reader.read_frame().transpose())),
+        });
+    }
+
+    // Anything that cannot be opened as a file is left for ffmpeg to open.
+    if let Ok(file) = File::open(path) {
+        let mut file = BufReader::new(file);
+        if file.fill_buf()?.starts_with(y4m::MAGIC) {
+            let mut reader = Y4mReader::new(file)?;
+            let (width, height, chroma) = (reader.width, reader.height, reader.chroma);
+            return Ok(Input {
+                width,
+                height,
+                frame_rate: reader.frame_rate,
+                frame_count: reader.frame_count(fs::metadata(path)?.len()),
+                range: reader.range,
+                pictures: Box::new(
+                    iter::from_fn(move || reader.read_frame().transpose())
+                        .map(move |planes| Ok(Picture::Yuv(planes?, chroma))),
+                ),
+            });
+        }
+    }
+
+    let mut decoder = Decoder::new(Path::new(path))?;
+    let (width, height) = decoder.size();
+    Ok(Input {
+        width: width as usize,
+        height: height as usize,
+        frame_rate: frame_rate_to_rational(decoder.frame_rate()),
+        frame_count: decoder.frames()? as usize,
+        range: None,
+        // The decoder reports the end of the stream as an error.
+        pictures: Box::new(
+            iter::from_fn(move || decoder.decode().ok()).map(|(_, frame)| Ok(Picture::Rgb(frame))),
+        ),
+    })
+}
+
+fn encode(args: &EncodeArgs) -> Result<()> {
     let mut writer = BitWriter::endian(
-        BufWriter::with_capacity(20 * 1024 * 1024, File::create(outfile)?),
+        BufWriter::with_capacity(20 * 1024 * 1024, File::create(&args.outfile)?),
         BigEndian,
     );
-    let mut decoder = Decoder::new(Path::new(infile))?;
+    let input = open_input(args)?;
+
+    let mut header = Header {
+        width: input.width,
+        height: input.height,
+        frame_rate: input.frame_rate,
+        frame_count: input.frame_count,
+        gop: args.gop as usize,
+        chroma: args.chroma,
+        matrix: args.matrix,
+        range: input.range.unwrap_or(args.range),
+        index_offset: 0,
+        luma_table: scale_quantization_table(&LUMA_QUANTIZATION_TABLE, args.quality),
+        chroma_table: scale_quantization_table(&CHROMA_QUANTIZATION_TABLE, args.quality),
+        huffman: None,
+        dc_prediction: args.dc == DcCoding::Predicted,
+        restart_interval: args.restart_interval,
+        skip_blocks: args.inter == Inter::Skip,
+        frame_quality: args.bitrate.is_some() || args.frame_budget.is_some(),
+    };
+
+    // Bits drained per frame and buffer size of the rate control model.
+    let (num, den) = header.frame_rate;
+    let rate = match (args.bitrate, args.frame_budget) {
+        (Some(kbps), _) => {
+            let bits = 1000 * kbps as u64;
+            let drain = bits * den as u64 / num as u64;
+            let size = args.buffer.map_or(bits, |bytes| 8 * bytes as u64);
+            Some((drain, size.max(drain)))
+        }
+        (None, Some(bytes)) => Some((8 * bytes as u64, 8 * bytes as u64)),
+        (None, None) => None,
+    };
+    let new_rate =
+        || rate.map(|(drain, size)| Mutex::new(RateControl::new(drain, size, args.constant_size)));
+
+    // The buffer carries over from one frame to the next unless it only
+    // holds a single frame, so rate controlled GOPs are then encoded in order.
+    let threads = match args.threads {
+        Some(threads) => threads as usize,
+        None => thread::available_parallelism()?.get(),
+    };
+    let threads = match rate {
+        Some((drain, size)) if size > drain => 1,
+        _ => threads,
+    };
+
+    if args.optimize {
+        // Frame sizes are measured with the default tables in this pass.
+        let codebook = HuffmanTable::for_header(&header)?;
+        let rate = new_rate();
+        let mut counts = SymbolCounts::new();
+        pipeline(
+            gops(
+                open_input(args)?
+                    .pictures
+                    .tqdm_with_bar(tqdm!(total = header.frame_count)),
+                header.gop,
+            ),
+            threads,
+            |frames| {
+                encode_gop(
+                    frames,
+                    &header,
+                    args.downsample,
+                    args.skip_threshold,
+                    &codebook,
+                    rate.as_ref(),
+                    args.rdo.then_some(args.lambda),
+                )
+            },
+            |frames| {
+                for frame in &frames? {
+                    counts.add(frame);
+                }
+                Ok(())
+            },
+        )?;
+        // Rate control and RDO quantize frames against the final tables, which
+        // may produce symbols this pass never counted.
+        if rate.is_some() || args.rdo {
+            counts.cover_defaults();
+        }
+        header.huffman = Some(counts.specs());
+    }
 
-    let frame_count = decoder.frames()? as usize;
-    let (width, height) = decoder.size();
-    let frame_rate = decoder.frame_rate() as u32;
-
-    // writer.write_bytes(b"tiny")?;
-    // writer.write_out::<16, _>(height as u16)?;
-    // writer.write_out::<16, _>(width as u16)?;
-    // writer.write_out::<16, _>(frame_rate as u16)?;
-    // writer.write_out::<16, _>(frame_count as u16)?;
-
-    decoder
-        .decode_iter()
-        .tqdm_with_bar(tqdm!(total = frame_count))
-        .take_while(Result::is_ok)
-        .for_each(|frame| {
-            let frame = encode_frame(frame.unwrap().1);
-            entropy_encode(&frame, &mut writer, &codebook);
-        });
+    let codebook = HuffmanTable::for_header(&header)?;
+    let rate = new_rate();
+    header.write(&mut writer)?;
+
+    let mut offset = writer
+        .writer()
+        .ok_or_else(|| anyhow!("Header is not byte aligned"))?
+        .stream_position()?;
+    let mut offsets = Vec::with_capacity(header.frame_count);
+    let mut bytes = 0;
+    let mut slack = Vec::new();
+
+    pipeline(
+        gops(
+            input
+                .pictures
+                .tqdm_with_bar(tqdm!(total = header.frame_count)),
+            header.gop,
+        ),
+        threads,
+        |frames| {
+            encode_gop(
+                frames,
+                &header,
+                args.downsample,
+                args.skip_threshold,
+                &codebook,
+                rate.as_ref(),
+                args.rdo.then_some(args.lambda),
+            )?
+            .iter()
+            .map(|frame| frame_payload(frame, &header, &codebook))
+            .collect::<Result<Vec<_>>>()
+        },
+        |payloads| {
+            for mut data in payloads? {
+                if let Some(budget) = args.frame_budget.filter(|_| args.constant_size) {
+                    slack.push(budget as usize - data.len());
+                    data.resize(budget as usize, 0);
+                }
+                write_frame(&mut writer, &data)?;
+                offsets.push(offset);
+                offset += 4 + data.len() as u64;
+                bytes += data.len() as u64;
+            }
+            Ok(())
+        },
+    )?;
 
-    writer.byte_align()?;
+    write_index(&mut writer, &offsets)?;
     writer.flush()?;
 
+    // The container's frame count is only an estimate and the index position
+    // is not known up front, so patch the header now that both are settled.
+    header.frame_count = offsets.len();
+    header.index_offset = offset;
+
+    let mut file = writer.into_writer();
+    file.seek(SeekFrom::Start(0))?;
+    header.write(&mut BitWriter::endian(&mut file, BigEndian))?;
+    file.flush()?;
+
+    let seconds = header.frame_count as f64 * den as f64 / num as f64;
+    println!(
+        "{} frames in {} bytes, {:.1} kbit/s",
+        header.frame_count,
+        bytes,
+        8.0 * bytes as f64 / seconds / 1000.0
+    );
+    if let Some(rate) = rate {
+        println!(
+            "{} frames overflowed the rate control buffer",
+            rate.lock().unwrap().overflows()
+        );
+    }
+    if let (Some(min), Some(max)) = (slack.iter().min(), slack.iter().max()) {
+        println!(
+            "Slack per frame: min {} bytes, mean {:.1} bytes, max {} bytes",
+            min,
+            slack.iter().sum::<usize>() as f64 / slack.len() as f64,
+            max
+        );
+    }
+
     Ok(())
 }
 
-fn decode(infile: &str, outfile: &str) -> Result<()> {
-    let codebook = HuffmanTable::new()?;
+/// Open a stream and read its header.
+fn open_stream(path: &str) -> Result<(BitReader<BufReader<File>, BigEndian>, Header)> {
     let mut reader = BitReader::endian(
-        BufReader::with_capacity(20 * 1024 * 1024, File::open(infile)?),
+        BufReader::with_capacity(20 * 1024 * 1024, File::open(path)?),
         BigEndian,
     );
-    let mut header = [0u8; 4];
+    let header = Header::read(&mut reader)?;
 
-    reader.read_bytes(&mut header)?;
+    Ok((reader, header))
+}
 
-    if !header.eq(b"tiny") {
+/// Decode `count` frames of a stream from `start`, defaulting to the rest of
+/// the stream, and pass each one to `f` with its index and frame data.
+#[allow(clippy::too_many_arguments)]
+fn decode_range<R, F>(
+    reader: &mut BitReader<R, BigEndian>,
+    header: &Header,
+    start: usize,
+    count: Option<usize>,
+    idct: Idct,
+    threads: Option<u32>,
+    mut f: F,
+) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(usize, &[u8], &Planes) -> Result<()>,
+{
+    let codebook = HuffmanTable::for_header(header)?;
+    let threads = match threads {
+        Some(threads) => threads as usize,
+        None => thread::available_parallelism()?.get(),
+    };
+
+    if start > 0 && start >= header.frame_count {
         return Err(anyhow!(
-            "Invalid header: {:?}",
-            str::from_utf8(&header).unwrap()
+            "Requested start frame {} but the stream only has {} frames",
+            start,
+            header.frame_count
         ));
     }
 
-    let height = reader.read_in::<16, u32>().unwrap() as usize;
-    let width = reader.read_in::<16, u32>().unwrap() as usize;
-    let frame_rate = reader.read_in::<16, u32>().unwrap() as usize;
-    let frame_count = reader.read_in::<16, u32>().unwrap() as usize;
+    let count = count.unwrap_or(header.frame_count - start);
+    if start + count > header.frame_count {
+        return Err(anyhow!(
+            "Requested frames {}..{} but the stream only has {} frames",
+            start,
+            start + count,
+            header.frame_count
+        ));
+    }
+    if count == 0 {
+        return Ok(());
+    }
+
+    // P-frames need their reference, so decoding starts from the I-frame
+    // at or before `start` and discards the frames in between.
+    let first = start - start % header.gop;
+    if first > 0 {
+        let index = read_index(reader, header)?;
+        reader.seek_bits(SeekFrom::Start(index[first] * 8))?;
+    }
+
+    let mut reference = None;
+    for index in tqdm!(first..start + count) {
+        let data = read_frame(reader)?;
+        let planes = decode_planes(
+            &data,
+            &codebook,
+            header,
+            reference.as_ref().filter(|_| !header.is_keyframe(index)),
+            idct,
+            threads,
+        )?;
+
+        if index >= start {
+            f(index, &data, &planes)?;
+        }
+
+        reference = Some(planes);
+    }
+
+    Ok(())
+}
+
+fn decode(args: &DecodeArgs) -> Result<()> {
+    let (mut reader, header) = open_stream(&args.infile)?;
+    let (num, den) = header.frame_rate;
+
+    if args.raw {
+        return decode_raw(args, &mut reader, &header);
+    }
+    if args.format == Format::MjpegAvi {
+        if args.deblock {
+            return Err(anyhow!(
+                "--deblock is not supported with --format mjpeg-avi"
+            ));
+        }
+        return decode_mjpeg_avi(args, &mut reader, &header);
+    }
+    if args.format == Format::Y4m {
+        return decode_y4m(args, &mut reader, &header);
+    }
 
     let mut encoder = Encoder::new(
-        Path::new(outfile),
-        Settings::preset_h264_yuv420p(width, height, false),
+        Path::new(&args.outfile),
+        Settings::preset_h264_yuv420p(header.width, header.height, false),
     )?;
 
-    let duration = Time::from_nth_of_a_second(frame_rate);
+    let duration = Time::from_secs_f64(den as f64 / num as f64);
     let mut position = Time::zero();
 
-    for _ in tqdm!(0..frame_count) {
-        let frame = decode_frame(&mut reader, &codebook, height, width);
-        encoder.encode(&frame, position).unwrap();
-        position = position.aligned_with(duration).add();
-    }
+    decode_range(
+        &mut reader,
+        &header,
+        args.start,
+        args.count,
+        args.idct,
+        args.threads,
+        |_, data, planes| {
+            let frame = if args.deblock {
+                let (quality, _) = frame_quality(data, &header)?;
+                let output = deblock_planes(planes, &header, quality);
+                planes_to_frame(&output, &header, args.upsample)
+            } else {
+                planes_to_frame(planes, &header, args.upsample)
+            };
+            encoder.encode(&frame, position)?;
+            position = position.aligned_with(duration).add();
+
+            Ok(())
+        },
+    )?;
 
     encoder.finish()?;
 
     Ok(())
 }
 
+/// Decode into a Y4M file of the decoded planes.
+fn decode_y4m<R>(
+    args: &DecodeArgs,
+    reader: &mut BitReader<R, BigEndian>,
+    header: &Header,
+) -> Result<()>
+where
+    R: Read + Seek,
+{
+    let out = BufWriter::new(File::create(&args.outfile)?);
+    let mut y4m = Y4mWriter::new(out, header)?;
+
+    decode_range(
+        reader,
+        header,
+        args.start,
+        args.count,
+        args.idct,
+        args.threads,
+        |_, data, planes| {
+            if args.deblock {
+                let (quality, _) = frame_quality(data, header)?;
+                y4m.write_frame(&deblock_planes(planes, header, quality))
+            } else {
+                y4m.write_frame(planes)
+            }
+        },
+    )?;
+
+    y4m.finish()
+}
+
+/// Decode into a headerless file of frames in the layout of `--pix-fmt`.
+fn decode_raw<R>(
+    args: &DecodeArgs,
+    reader: &mut BitReader<R, BigEndian>,
+    header: &Header,
+) -> Result<()>
+where
+    R: Read + Seek,
+{
+    let mut out = BufWriter::new(File::create(&args.outfile)?);
+
+    decode_range(
+        reader,
+        header,
+        args.start,
+        args.count,
+        args.idct,
+        args.threads,
+        |_, data, planes| {
+            if args.deblock {
+                let (quality, _) = frame_quality(data, header)?;
+                let output = deblock_planes(planes, header, quality);
+                write_raw_frame(&mut out, &output, header, args.pix_fmt, args.upsample)
+            } else {
+                write_raw_frame(&mut out, planes, header, args.pix_fmt, args.upsample)
+            }
+        },
+    )?;
+
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Decode into a Motion JPEG AVI file, with every frame coded as by
+/// [`frame_to_jpeg`].
+fn decode_mjpeg_avi<R>(
+    args: &DecodeArgs,
+    reader: &mut BitReader<R, BigEndian>,
+    header: &Header,
+) -> Result<()>
+where
+    R: Read + Seek,
+{
+    let codebook = HuffmanTable::for_header(header)?;
+    let out = BufWriter::new(File::create(&args.outfile)?);
+    let mut avi = AviWriter::new(out, header.width, header.height, header.frame_rate)?;
+
+    decode_range(
+        reader,
+        header,
+        args.start,
+        args.count,
+        args.idct,
+        args.threads,
+        |index, data, planes| {
+            avi.write_frame(&frame_to_jpeg(index, data, planes, header, &codebook)?)
+        },
+    )?;
+
+    avi.finish()?;
+
+    Ok(())
+}
+
+/// Code a decoded frame as a baseline JPEG file.
+///
+/// I-frames keep their coefficients, decoded with the stream's `codebook`.
+/// P-frames are quantized again from the decoded `planes` with the frame's
+/// tables.
+fn frame_to_jpeg(
+    index: usize,
+    data: &[u8],
+    planes: &Planes,
+    header: &Header,
+    codebook: &HuffmanTable,
+) -> Result<Vec<u8>> {
+    let (quality, payload) = frame_quality(data, header)?;
+    let blocks = if header.is_keyframe(index) {
+        decode_coefficients(payload, codebook, header)?
+    } else {
+        requantize(planes, header, quality)
+    };
+    let [(_, luma), (_, chroma), _] = plane_params(header, quality);
+
+    let mut out = Vec::new();
+    write_jpeg(
+        &mut out,
+        header,
+        [&blocks[0], &blocks[1], &blocks[2]],
+        [&luma, &chroma],
+    )?;
+
+    Ok(out)
+}
+
+fn export_jpeg(args: &ExportJpegArgs) -> Result<()> {
+    let (mut reader, header) = open_stream(&args.infile)?;
+    let codebook = HuffmanTable::for_header(&header)?;
+    fs::create_dir_all(&args.outdir)?;
+
+    decode_range(
+        &mut reader,
+        &header,
+        args.start,
+        args.count,
+        Idct::Float,
+        args.threads,
+        |index, data, planes| {
+            let jpeg = frame_to_jpeg(index, data, planes, &header, &codebook)?;
+            let path = Path::new(&args.outdir).join(format!("frame_{index:05}.jpg"));
+            fs::write(path, jpeg)?;
+
+            Ok(())
+        },
+    )
+}
+
+/// Write a frame as a binary PPM image.
+fn write_ppm(path: &str, frame: &Frame) -> Result<()> {
+    let (height, width, _) = frame.dim();
+    let mut out = BufWriter::new(File::create(path)?);
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+    out.write_all(&frame.iter().copied().collect::<Vec<_>>())?;
+    out.flush()?;
+
+    Ok(())
+}
+
+fn import_jpeg(args: &ImportJpegArgs) -> Result<()> {
+    let JpegImage {
+        header,
+        mut blocks,
+        tables,
+    } = read_jpeg(&fs::read(&args.infile)?)?;
+    let transform: Transform = match args.idct {
+        Idct::Float => idct,
+        Idct::Hw => hw_idct,
+    };
+
+    for (blocks, table) in blocks.iter_mut().zip(&tables) {
+        reconstruct_blocks(blocks.view_mut(), table, false, transform);
+    }
+    let planes = compose_planes(blocks, &header, None);
+
+    write_ppm(
+        &args.outfile,
+        &planes_to_frame(&planes, &header, args.upsample),
+    )
+}
+
+fn time_transform(
+    name: &str,
+    blocks: &Array2<i64>,
+    table: &[i64; 64],
+    transform: Transform,
+) -> Array2<i64> {
+    let mut out = blocks.clone();
+    let start = Instant::now();
+    transform(out.view_mut(), table);
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>10.2?} {:>8.1} ns/block",
+        name,
+        elapsed,
+        elapsed.as_nanos() as f64 / blocks.nrows() as f64
+    );
+    out
+}
+
+fn bench(args: &BenchArgs) -> Result<()> {
+    let table = scale_quantization_table(&LUMA_QUANTIZATION_TABLE, args.quality);
+
+    // Smooth gradients plus xorshift noise, so the coefficients look like
+    // those of real image blocks rather than white noise.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let pixels = Array2::from_shape_fn((args.blocks, 64), |(n, i)| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let ramp = (n % 7) as i64 * (i / 8) as i64 + (n % 5) as i64 * (i % 8) as i64;
+        (64 + ramp + (state % 32) as i64).clamp(0, 255)
+    });
+
+    let expected = time_transform("matrix fdct", &pixels, &table, matrix_fdct);
+    let coefficients = time_transform("fast fdct", &pixels, &table, fdct);
+    let fdct_mismatches = expected
+        .iter()
+        .zip(coefficients.iter())
+        .filter(|(a, b)| a != b)
+        .count();
+
+    // Both inverse transforms see the same coefficients, so any mismatch is
+    // down to the transform itself.
+    let expected = time_transform("matrix idct", &coefficients, &table, matrix_idct);
+    let samples = time_transform("fast idct", &coefficients, &table, idct);
+    let idct_mismatches = expected
+        .iter()
+        .zip(samples.iter())
+        .filter(|(a, b)| a != b)
+        .count();
+
+    println!(
+        "mismatches: {} of {} coefficients, {} of {} samples",
+        fdct_mismatches,
+        pixels.len(),
+        idct_mismatches,
+        pixels.len()
+    );
+
+    Ok(())
+}
+
 /// Parse command line arguments and execute the corresponding command.
 fn main() -> Result<()> {
     match &Cli::parse().command {
-        Commands::Encode { infile, outfile } => encode(infile, outfile),
-        Commands::Decode { infile, outfile } => decode(infile, outfile),
+        Commands::Encode(args) => encode(args),
+        Commands::Decode(args) => decode(args),
+        Commands::ExportJpeg(args) => export_jpeg(args),
+        Commands::ImportJpeg(args) => import_jpeg(args),
+        Commands::Bench(args) => bench(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header of a stream of `width` x `height` frames with the base tables
+    /// scaled to `quality`.
+    fn header(width: usize, height: usize, chroma: Chroma, gop: usize, quality: u8) -> Header {
+        Header {
+            width,
+            height,
+            frame_rate: (30, 1),
+            frame_count: 0,
+            gop,
+            chroma,
+            matrix: Matrix::Bt601,
+            range: Range::Full,
+            index_offset: 0,
+            luma_table: scale_quantization_table(&LUMA_QUANTIZATION_TABLE, quality),
+            chroma_table: scale_quantization_table(&CHROMA_QUANTIZATION_TABLE, quality),
+            huffman: None,
+            dc_prediction: true,
+            restart_interval: None,
+            skip_blocks: false,
+            frame_quality: false,
+        }
+    }
+
+    /// A one-pixel checkerboard of magenta and green, which have the same
+    /// luma, with the colours swapped for odd `phase`.
+    fn checkerboard(width: usize, height: usize, phase: usize) -> Picture {
+        Picture::Rgb(Array3::from_shape_fn((height, width, 3), |(i, j, c)| {
+            let colour = if (i + j + phase) % 2 == 1 {
+                [255, 0, 255]
+            } else {
+                [0, 179, 0]
+            };
+            colour[c]
+        }))
+    }
+
+    /// A smooth colour pattern moved by two pixels right and one down in
+    /// every frame `n`.
+    fn moving(width: usize, height: usize, n: usize) -> Picture {
+        Picture::Rgb(Array3::from_shape_fn((height, width, 3), |(i, j, c)| {
+            let (y, x) = (i as f64 - n as f64, j as f64 - 2.0 * n as f64);
+            let v = (x / 5.0 + c as f64).sin() * 60.0 + (y / 7.0).cos() * 40.0;
+            (128.0 + v) as u8
+        }))
+    }
+
+    /// Encode `frames` into an in-memory stream and open it for decoding.
+    fn stream(
+        mut header: Header,
+        frames: Vec<Picture>,
+    ) -> (BitReader<io::Cursor<Vec<u8>>, BigEndian>, Header) {
+        let codebook = HuffmanTable::new().unwrap();
+        let mut payloads = Vec::new();
+        for gop in gops(frames.into_iter().map(Ok), header.gop) {
+            let encoded = encode_gop(
+                gop.unwrap(),
+                &header,
+                Downsample::Box,
+                0,
+                &codebook,
+                None,
+                None,
+            );
+            for frame in encoded.unwrap() {
+                payloads.push(frame_payload(&frame, &header, &codebook).unwrap());
+            }
+        }
+
+        let mut bits = BitWriter::endian(Vec::new(), BigEndian);
+        header.write(&mut bits).unwrap();
+        let mut offset = bits.into_writer().len() as u64;
+        let mut offsets = Vec::new();
+        for data in &payloads {
+            offsets.push(offset);
+            offset += 4 + data.len() as u64;
+        }
+        header.frame_count = payloads.len();
+        header.index_offset = offset;
+
+        let mut bits = BitWriter::endian(Vec::new(), BigEndian);
+        header.write(&mut bits).unwrap();
+        for data in &payloads {
+            write_frame(&mut bits, data).unwrap();
+        }
+        write_index(&mut bits, &offsets).unwrap();
+
+        let mut reader = BitReader::endian(io::Cursor::new(bits.into_writer()), BigEndian);
+        let header = Header::read(&mut reader).unwrap();
+        (reader, header)
+    }
+
+    /// Indices of the frames `decode_range` passes on for `start` and `count`.
+    fn decoded_frames(start: usize, count: Option<usize>) -> Result<Vec<usize>> {
+        let frames = (0..6).map(|n| checkerboard(16, 16, n)).collect();
+        let (mut reader, header) = stream(header(16, 16, Chroma::Yuv420, 2, 50), frames);
+
+        let mut decoded = Vec::new();
+        decode_range(
+            &mut reader,
+            &header,
+            start,
+            count,
+            Idct::Float,
+            Some(1),
+            |index, _, _| {
+                decoded.push(index);
+                Ok(())
+            },
+        )?;
+        Ok(decoded)
+    }
+
+    #[test]
+    fn decode_range_bounds() {
+        assert_eq!(decoded_frames(0, None).unwrap(), (0..6).collect::<Vec<_>>());
+        assert_eq!(decoded_frames(3, Some(2)).unwrap(), [3, 4]);
+        assert_eq!(decoded_frames(5, None).unwrap(), [5]);
+        assert_eq!(decoded_frames(2, Some(0)).unwrap(), []);
+        assert!(decoded_frames(6, None).is_err());
+        assert!(decoded_frames(6, Some(0)).is_err());
+        assert!(decoded_frames(4, Some(3)).is_err());
+    }
+
+    /// Every frame of a stream as `decode` reconstructs it.
+    fn decode_all(data: Vec<u8>) -> Vec<Planes> {
+        let mut reader = BitReader::endian(io::Cursor::new(data), BigEndian);
+        let header = Header::read(&mut reader).unwrap();
+
+        let mut decoded = Vec::new();
+        decode_range(
+            &mut reader,
+            &header,
+            0,
+            None,
+            Idct::Float,
+            Some(1),
+            |_, _, planes| {
+                decoded.push(planes.clone());
+                Ok(())
+            },
+        )
+        .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn decodes_version_8_streams() {
+        let frames = (0..3).map(|n| moving(24, 16, n)).collect();
+        let (reader, header) = stream(header(24, 16, Chroma::Yuv420, 1, 50), frames);
+        let data = reader.into_reader().into_inner();
+
+        // Version 8 has no GOP size at bytes 21 and 22, so the index offset
+        // and every frame offset move back by two bytes.
+        let mut old = data.clone();
+        old[4] = 8;
+        old.drain(21..23);
+        let index = header.index_offset as usize - 2;
+        old[26..34].copy_from_slice(&(index as u64).to_be_bytes());
+        for entry in old[index + 8..].chunks_exact_mut(8) {
+            let offset = u64::from_be_bytes(entry.try_into().unwrap());
+            entry.copy_from_slice(&(offset - 2).to_be_bytes());
+        }
+
+        let (expected, decoded) = (decode_all(data), decode_all(old));
+        assert_eq!(decoded.len(), 3);
+        for (a, b) in decoded.iter().zip(&expected) {
+            assert_eq!(a.planes(), b.planes());
+        }
+    }
+
+    #[test]
+    fn write_symbol_rejects_symbols_without_a_code() {
+        let codebook = HuffmanTable::new().unwrap();
+        let mut writer = BitWriter::endian(Vec::new(), BigEndian);
+
+        assert!(write_symbol(&mut writer, &codebook, Symbol::Ac((0, 10), 0)).is_ok());
+        assert!(write_symbol(&mut writer, &codebook, Symbol::Ac((0, 11), 0)).is_err());
+        assert!(write_symbol(&mut writer, &codebook, Symbol::Dc(12, 0)).is_err());
+    }
+
+    /// Error message of entropy decoding one block of `symbols` followed by
+    /// `ones` one bits.
+    fn entropy_decode_err(symbols: Vec<Symbol>, ones: u32) -> String {
+        let codebook = HuffmanTable::new().unwrap();
+        let mut writer = BitWriter::endian(Vec::new(), BigEndian);
+        for symbol in symbols {
+            write_symbol(&mut writer, &codebook, symbol).unwrap();
+        }
+        for _ in 0..ones {
+            writer.write_bit(true).unwrap();
+        }
+        writer.byte_align().unwrap();
+
+        let data = writer.into_writer();
+        let mut reader = BitReader::endian(data.as_slice(), BigEndian);
+        entropy_decode(&mut reader, &codebook, 1)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn entropy_decode_rejects_invalid_blocks() {
+        // Three runs of 16 zeros and one of 15 run past coefficient 63.
+        let zrl = || Symbol::Ac((15, 0), 0);
+        let symbols = vec![
+            Symbol::Dc(0, 0),
+            zrl(),
+            zrl(),
+            zrl(),
+            Symbol::Ac((15, 1), 1),
+        ];
+        assert!(entropy_decode_err(symbols, 0).contains("past the end"));
+
+        // The all-ones code is the filler of the AC table.
+        let symbols = vec![Symbol::Dc(0, 0)];
+        assert!(entropy_decode_err(symbols, 16).contains("Invalid AC code"));
+
+        // Data that ends inside a block, whatever the padding decodes to.
+        entropy_decode_err(vec![Symbol::Dc(0, 0), Symbol::Ac((0, 1), 1)], 0);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let codebook = HuffmanTable::new().unwrap();
+        for (restart_interval, skip_blocks) in [(None, false), (Some(1), false), (None, true)] {
+            let header = Header {
+                restart_interval,
+                skip_blocks,
+                ..header(40, 24, Chroma::Yuv420, 2, 75)
+            };
+            let frames = (0..2).map(|n| moving(40, 24, n)).collect();
+            let encoded =
+                encode_gop(frames, &header, Downsample::Box, 500, &codebook, None, None).unwrap();
+
+            let reference = reconstruct(&encoded[0], &header, None);
+            for (frame, reference) in encoded.iter().zip([None, Some(&reference)]) {
+                let data = frame_payload(frame, &header, &codebook).unwrap();
+                let data = &data[..data.len() / 2];
+                let decoded = decode_planes(data, &codebook, &header, reference, Idct::Float, 2);
+                assert!(decoded.is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn residuals_at_quality_100_fit_the_builtin_tables() {
+        // The luma is flat, so motion search keeps the zero vector and every
+        // P-frame is a full-scale chroma residual on the highest frequency
+        // basis function.
+        let header = header(32, 32, Chroma::Yuv444, 4, 100);
+        let codebook = HuffmanTable::new().unwrap();
+        let frames = (0..4).map(|n| checkerboard(32, 32, n)).collect();
+
+        for frame in
+            &encode_gop(frames, &header, Downsample::Box, 0, &codebook, None, None).unwrap()
+        {
+            frame_payload(frame, &header, &codebook).unwrap();
+        }
+    }
+
+    #[test]
+    fn p_frames_decode_to_the_encoder_reconstruction() {
+        let codebook = HuffmanTable::new().unwrap();
+        for (restart_interval, skip_blocks) in [(None, false), (Some(1), false), (None, true)] {
+            let header = Header {
+                restart_interval,
+                skip_blocks,
+                ..header(40, 24, Chroma::Yuv420, 4, 75)
+            };
+            let frames = (0..4).map(|n| moving(40, 24, n)).collect();
+
+            let mut reference: Option<Planes> = None;
+            for frame in
+                encode_gop(frames, &header, Downsample::Box, 500, &codebook, None, None).unwrap()
+            {
+                let inter = reference.is_some() && !skip_blocks;
+                assert_eq!(frame.motion.is_some(), inter);
+                let expected = reconstruct(&frame, &header, reference.as_ref());
+                let data = frame_payload(&frame, &header, &codebook).unwrap();
+                let decoded = decode_planes(
+                    &data,
+                    &codebook,
+                    &header,
+                    reference.as_ref(),
+                    Idct::Float,
+                    2,
+                )
+                .unwrap();
+                assert_eq!(decoded.planes(), expected.planes());
+                reference = Some(decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn strict_rate_control_cuts_frames_to_the_budget() {
+        // Full-scale chroma does not fit in 300 bytes even at quality 1.
+        let header = Header {
+            frame_quality: true,
+            ..header(32, 32, Chroma::Yuv444, 4, 50)
+        };
+        let codebook = HuffmanTable::new().unwrap();
+        let rate = Mutex::new(RateControl::new(8 * 300, 8 * 300, true));
+        let frames = (0..4).map(|n| checkerboard(32, 32, n)).collect();
+
+        let encoded = encode_gop(
+            frames,
+            &header,
+            Downsample::Box,
+            0,
+            &codebook,
+            Some(&rate),
+            None,
+        );
+        for frame in &encoded.unwrap() {
+            assert!(frame_payload(frame, &header, &codebook).unwrap().len() <= 300);
+        }
+        assert_eq!(rate.lock().unwrap().overflows(), 0);
     }
 }
@@ -0,0 +1,98 @@
+use ndarray::{prelude::*, Zip};
+
+/// Height and width in luma samples of the area sharing one motion vector.
+pub const MACROBLOCK: usize = 16;
+
+/// Largest displacement in luma samples tried by [`estimate`] in each
+/// direction.
+const SEARCH_RANGE: isize = 7;
+
+/// Number of macroblock rows and columns covering a `height` x `width` luma
+/// plane. Macroblocks on the bottom and right edges may be partial.
+pub fn macroblocks(height: usize, width: usize) -> (usize, usize) {
+    (height.div_ceil(MACROBLOCK), width.div_ceil(MACROBLOCK))
+}
+
+/// Sum of absolute differences between `block` and the equally sized area of
+/// `reference` whose top-left corner is at `(y, x)`.
+fn sad(block: ArrayView2<u8>, reference: ArrayView2<u8>, y: usize, x: usize) -> u32 {
+    let (h, w) = block.dim();
+    Zip::from(block)
+        .and(reference.slice(s![y..y + h, x..x + w]))
+        .fold(0, |sum, &a, &b| sum + a.abs_diff(b) as u32)
+}
+
+/// Find a motion vector for every macroblock of the `current` luma plane by
+/// exhaustive block matching against the `reference` luma plane.
+///
+/// Each vector `(dy, dx)` minimises the sum of absolute differences over the
+/// displacements within [`SEARCH_RANGE`] that keep the block inside the
+/// plane. The zero vector is tried first and only a strictly better match
+/// replaces the current best, so static areas keep a zero vector. The result
+/// has shape `(rows, cols, 2)`.
+pub fn estimate(current: ArrayView2<u8>, reference: ArrayView2<u8>) -> Array3<i64> {
+    let (h, w) = current.dim();
+    let (rows, cols) = macroblocks(h, w);
+    let mut motion = Array3::zeros((rows, cols, 2));
+
+    for r in 0..rows {
+        for c in 0..cols {
+            let (y, x) = (r * MACROBLOCK, c * MACROBLOCK);
+            let block = current.slice(s![y..(y + MACROBLOCK).min(h), x..(x + MACROBLOCK).min(w)]);
+            let (bh, bw) = block.dim();
+
+            let mut best = (0, 0);
+            let mut min_sad = sad(block, reference, y, x);
+
+            for dy in -SEARCH_RANGE..=SEARCH_RANGE {
+                for dx in -SEARCH_RANGE..=SEARCH_RANGE {
+                    let (ry, rx) = (y as isize + dy, x as isize + dx);
+                    if min_sad == 0
+                        || ry < 0
+                        || rx < 0
+                        || ry as usize + bh > h
+                        || rx as usize + bw > w
+                    {
+                        continue;
+                    }
+
+                    let cost = sad(block, reference, ry as usize, rx as usize);
+                    if cost < min_sad {
+                        min_sad = cost;
+                        best = (dy, dx);
+                    }
+                }
+            }
+
+            motion[[r, c, 0]] = best.0 as i64;
+            motion[[r, c, 1]] = best.1 as i64;
+        }
+    }
+
+    motion
+}
+
+/// Predict a plane by moving each macroblock of `reference` by its vector.
+///
+/// For a chroma plane decimated by `(sy, sx)` the macroblocks shrink by the
+/// same factors and the vectors are divided by them, rounding toward zero.
+/// Samples outside the reference are taken from its nearest edge, so any
+/// vector read from a stream is safe to apply.
+pub fn compensate(
+    reference: ArrayView2<u8>,
+    motion: ArrayView3<i64>,
+    (sy, sx): (usize, usize),
+) -> Array2<u8> {
+    let (h, w) = reference.dim();
+    let (bh, bw) = (MACROBLOCK / sy, MACROBLOCK / sx);
+
+    Array2::from_shape_fn((h, w), |(i, j)| {
+        let (r, c) = (i / bh, j / bw);
+        let y = i as i64 + motion[[r, c, 0]] / sy as i64;
+        let x = j as i64 + motion[[r, c, 1]] / sx as i64;
+        reference[[
+            y.clamp(0, h as i64 - 1) as usize,
+            x.clamp(0, w as i64 - 1) as usize,
+        ]]
+    })
+}
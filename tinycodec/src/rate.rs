@@ -0,0 +1,153 @@
+use anyhow::Result;
+
+/// Leaky bucket model of the buffer between the encoder and a constant rate
+/// channel.
+///
+/// Every coded frame adds its bits to the buffer and the channel then drains
+/// `drain` bits per frame interval. A frame that does not fit in the free
+/// space of the buffer overflows it.
+#[derive(Debug)]
+pub struct RateControl {
+    drain: u64,
+    size: u64,
+    fullness: u64,
+    overflows: usize,
+    strict: bool,
+}
+
+impl RateControl {
+    /// A buffer of `size` bits, drained by `drain` bits per frame. The buffer
+    /// always holds at least one frame's worth and starts out at the
+    /// [`level`](Self::level) that rate control aims for.
+    ///
+    /// A `strict` buffer must never overflow, so frames that miss the target
+    /// at every quality are cut down further by the encoder.
+    pub fn new(drain: u64, size: u64, strict: bool) -> Self {
+        let size = size.max(drain);
+        RateControl {
+            drain,
+            size,
+            fullness: (size - drain) / 2,
+            overflows: 0,
+            strict,
+        }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Fullness after draining that leaves as much headroom for a frame
+    /// larger than the drain rate as for a run of smaller frames.
+    fn level(&self) -> u64 {
+        (self.size - self.drain) / 2
+    }
+
+    /// Number of bits the next frame should be coded in.
+    ///
+    /// The target is the drain rate, corrected by a quarter of the distance
+    /// from the [`level`](Self::level), and never more than the free space.
+    pub fn target(&self) -> u64 {
+        let correction = self.level() as i64 - self.fullness as i64;
+        let target = (self.drain as i64 + correction / 4).max(self.drain as i64 / 4);
+        (target as u64).min(self.size - self.fullness)
+    }
+
+    /// Add a coded frame of `bits` bits to the buffer and drain it.
+    pub fn commit(&mut self, bits: u64) {
+        let level = self.fullness + bits;
+        if level > self.size {
+            self.overflows += 1;
+        }
+        self.fullness = level.min(self.size).saturating_sub(self.drain);
+    }
+
+    /// Number of frames that did not fit in the buffer so far.
+    pub fn overflows(&self) -> usize {
+        self.overflows
+    }
+}
+
+/// Find the largest value from `low` to `high` whose frame, of `bits(value)`
+/// bits, is no larger than `target` by a binary search, assuming the size
+/// grows with the value. Returns the value and the size of its frame, or
+/// `None` if even `low` does not fit.
+pub fn search<F>(low: usize, high: usize, target: u64, mut bits: F) -> Result<Option<(usize, u64)>>
+where
+    F: FnMut(usize) -> Result<u64>,
+{
+    let (mut low, mut high) = (low, high);
+    let mut best = None;
+
+    while low <= high {
+        let value = low + (high - low) / 2;
+        let size = bits(value)?;
+        if size <= target {
+            best = Some((value, size));
+            low = value + 1;
+        } else if value == low {
+            break;
+        } else {
+            high = value - 1;
+        }
+    }
+
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_finds_the_highest_value_within_the_target() {
+        let bits = |quality: usize| Ok(100 * quality as u64);
+        assert_eq!(search(1, 100, 4250, bits).unwrap(), Some((42, 4200)));
+        assert_eq!(search(1, 100, 4200, bits).unwrap(), Some((42, 4200)));
+        assert_eq!(search(1, 100, 20_000, bits).unwrap(), Some((100, 10_000)));
+        assert_eq!(search(1, 100, 50, bits).unwrap(), None);
+        assert_eq!(search(10, 20, 500, bits).unwrap(), None);
+    }
+
+    #[test]
+    fn leaky_bucket() {
+        let mut rate = RateControl::new(1000, 4000, false);
+        assert_eq!(rate.fullness, 1500);
+        assert_eq!(rate.target(), 1000);
+
+        rate.commit(1000);
+        assert_eq!((rate.fullness, rate.overflows()), (1500, 0));
+
+        // 4500 bits do not fit in the buffer, which is left full and drained.
+        rate.commit(3000);
+        assert_eq!((rate.fullness, rate.overflows()), (3000, 1));
+        assert_eq!(rate.target(), 625);
+
+        rate.commit(0);
+        assert_eq!((rate.fullness, rate.overflows()), (2000, 1));
+        rate.commit(0);
+        rate.commit(0);
+        assert_eq!(rate.fullness, 0);
+
+        // The buffer holds at least one frame.
+        assert_eq!(RateControl::new(1000, 10, false).size, 1000);
+    }
+
+    #[test]
+    fn constant_size_frames_never_overflow() {
+        // A frame budget drains a whole buffer per frame.
+        let mut rate = RateControl::new(8000, 8000, true);
+        assert!(rate.is_strict());
+        assert_eq!(rate.target(), 8000);
+
+        // Frames padded to exactly their budget fill the buffer to the brim.
+        for _ in 0..4 {
+            rate.commit(8000);
+            assert_eq!((rate.fullness, rate.overflows()), (0, 0));
+            assert_eq!(rate.target(), 8000);
+        }
+
+        rate.commit(8001);
+        assert_eq!(rate.overflows(), 1);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::header::{Chroma, Header};
+use crate::resample::{downsample, upsample, Downsample, Upsample};
+use crate::{planes_to_frame, Picture, Planes};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use ndarray::prelude::*;
+use std::io::{BufRead, Write};
+
+/// Layout of the frames of a headerless raw file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PixFmt {
+    /// Planar Y, U and V, with U and V decimated by two in both directions
+    /// and rounded up to whole samples.
+    Yuv420p,
+    /// Packed R, G and B.
+    Rgb24,
+    /// Luma only.
+    Gray,
+}
+
+impl PixFmt {
+    /// Size in bytes of a `width` x `height` frame.
+    pub fn frame_size(self, width: usize, height: usize) -> usize {
+        match self {
+            PixFmt::Yuv420p => width * height + 2 * width.div_ceil(2) * height.div_ceil(2),
+            PixFmt::Rgb24 => 3 * width * height,
+            PixFmt::Gray => width * height,
+        }
+    }
+}
+
+/// Parse a frame size given as `WxH`.
+pub fn parse_size(size: &str) -> Result<(usize, usize), String> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| format!("expected WxH, got {}", size))?;
+    let parse = |value: &str| match value.parse() {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err(format!("invalid dimension: {}", value)),
+    };
+
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// Reader of 8-bit frames from a headerless raw file.
+pub struct RawReader<R> {
+    reader: R,
+    width: usize,
+    height: usize,
+    pix_fmt: PixFmt,
+}
+
+impl<R> RawReader<R>
+where
+    R: BufRead,
+{
+    pub fn new(reader: R, width: usize, height: usize, pix_fmt: PixFmt) -> Self {
+        RawReader {
+            reader,
+            width,
+            height,
+            pix_fmt,
+        }
+    }
+
+    /// Read the next frame, or `None` at the end of the file.
+    pub fn read_frame(&mut self) -> Result<Option<Picture>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let (width, height) = (self.width, self.height);
+        let mut samples = vec![0; self.pix_fmt.frame_size(width, height)];
+        self.reader
+            .read_exact(&mut samples)
+            .map_err(|_| anyhow!("Raw file ends inside a frame"))?;
+
+        let (ch, cw) = (height.div_ceil(2), width.div_ceil(2));
+        let picture = match self.pix_fmt {
+            PixFmt::Yuv420p => {
+                let u = samples.split_off(width * height);
+                let v = u[ch * cw..].to_vec();
+                let planes = Planes {
+                    y: Array2::from_shape_vec((height, width), samples)?,
+                    u: Array2::from_shape_vec((ch, cw), u[..ch * cw].to_vec())?,
+                    v: Array2::from_shape_vec((ch, cw), v)?,
+                };
+                Picture::Yuv(planes, Chroma::Yuv420)
+            }
+            PixFmt::Rgb24 => Picture::Rgb(Array3::from_shape_vec((height, width, 3), samples)?),
+            PixFmt::Gray => {
+                let planes = Planes {
+                    y: Array2::from_shape_vec((height, width), samples)?,
+                    u: Array2::zeros((0, 0)),
+                    v: Array2::zeros((0, 0)),
+                };
+                Picture::Yuv(planes, Chroma::Gray)
+            }
+        };
+
+        Ok(Some(picture))
+    }
+}
+
+/// Write decoded planes as one raw frame, cropped to the frame size.
+///
+/// RGB is converted with the stream's colour matrix and range after
+/// restoring the chroma resolution with `filter`. For `yuv420p`, chroma in
+/// another layout is restored the same way and then averaged down, and grey
+/// streams get mid-grey chroma.
+pub fn write_raw_frame<W>(
+    out: &mut W,
+    planes: &Planes,
+    header: &Header,
+    pix_fmt: PixFmt,
+    filter: Upsample,
+) -> Result<()>
+where
+    W: Write,
+{
+    let (height, width) = (header.height, header.width);
+    let mut write_plane =
+        |plane: ArrayView2<u8>| out.write_all(&plane.iter().copied().collect::<Vec<_>>());
+
+    match pix_fmt {
+        PixFmt::Rgb24 => write_plane(
+            planes_to_frame(planes, header, filter)
+                .into_shape_with_order((height, 3 * width))?
+                .view(),
+        )?,
+        PixFmt::Gray => write_plane(planes.y.slice(s![..height, ..width]))?,
+        PixFmt::Yuv420p => {
+            write_plane(planes.y.slice(s![..height, ..width]))?;
+
+            let (ch, cw) = (height.div_ceil(2), width.div_ceil(2));
+            for plane in [&planes.u, &planes.v] {
+                let chroma = match header.chroma {
+                    Chroma::Yuv420 => plane.slice(s![..ch, ..cw]).to_owned(),
+                    Chroma::Gray => Array2::from_elem((ch, cw), 128),
+                    chroma => {
+                        let full =
+                            upsample(plane.view(), chroma.subsampling(), 2 * ch, 2 * cw, filter);
+                        downsample(full.view(), ch, cw, Downsample::Box)
+                    }
+                };
+                write_plane(chroma.view())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{Matrix, Range};
+    use crate::picture_planes;
+    use std::io::Cursor;
+
+    fn header(width: usize, height: usize, chroma: Chroma) -> Header {
+        Header {
+            width,
+            height,
+            frame_rate: (25, 1),
+            frame_count: 2,
+            gop: 1,
+            chroma,
+            matrix: Matrix::Bt601,
+            range: Range::Full,
+            index_offset: 0,
+            luma_table: [1; 64],
+            chroma_table: [1; 64],
+            huffman: None,
+            dc_prediction: true,
+            restart_interval: None,
+            skip_blocks: false,
+            frame_quality: false,
+        }
+    }
+
+    /// `frames` frames of pseudo-random samples in the layout of `pix_fmt`.
+    fn samples(pix_fmt: PixFmt, width: usize, height: usize, frames: usize) -> Vec<u8> {
+        let mut seed = 1u32;
+        (0..frames * pix_fmt.frame_size(width, height))
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (width, height) = (13, 5);
+        for (pix_fmt, chroma, tolerance) in [
+            (PixFmt::Yuv420p, Chroma::Yuv420, 0),
+            // RGB goes through the colour conversion both ways.
+            (PixFmt::Rgb24, Chroma::Yuv444, 1),
+            (PixFmt::Gray, Chroma::Gray, 0),
+        ] {
+            let header = header(width, height, chroma);
+            let data = samples(pix_fmt, width, height, 2);
+
+            let mut reader = RawReader::new(Cursor::new(&data), width, height, pix_fmt);
+            let mut out = Vec::new();
+            while let Some(picture) = reader.read_frame().unwrap() {
+                let planes = picture_planes(picture, &header, Downsample::Box);
+                write_raw_frame(&mut out, &planes, &header, pix_fmt, Upsample::Replicate).unwrap();
+            }
+
+            assert_eq!(out.len(), data.len());
+            for (a, b) in out.iter().zip(&data) {
+                assert!(a.abs_diff(*b) <= tolerance, "{:?}: {} != {}", pix_fmt, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn short_last_frame() {
+        let mut data = samples(PixFmt::Yuv420p, 13, 5, 2);
+        data.truncate(data.len() - 3);
+
+        let mut reader = RawReader::new(Cursor::new(&data), 13, 5, PixFmt::Yuv420p);
+        assert!(reader.read_frame().unwrap().is_some());
+        let error = reader.read_frame().err().unwrap();
+        assert_eq!(error.to_string(), "Raw file ends inside a frame");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("640x480"), Ok((640, 480)));
+        for size in [
+            "0x480", "640x0", "640", "640x", "x480", "ax480", "640x-1", "640X480",
+        ] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+    }
+}
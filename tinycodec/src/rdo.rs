@@ -0,0 +1,147 @@
+use crate::{for_each_symbol, HuffmanTable, SCAN_ORDER_TABLE};
+use ndarray::prelude::*;
+
+/// Number of bits `codebook` spends on one block of zigzagged coefficients,
+/// or `None` if it has no code for one of the block's symbols.
+fn block_bits(block: ArrayView1<i64>, codebook: &HuffmanTable) -> Option<u64> {
+    let mut bits = Some(0);
+    for_each_symbol(block.insert_axis(Axis(0)), |symbol| {
+        bits = bits
+            .zip(codebook.symbol_bits(&symbol))
+            .map(|(total, n)| total + n as u64);
+    });
+    bits
+}
+
+/// Rate-distortion optimize quantized blocks in place.
+///
+/// `blocks` hold zigzagged coefficients and `quotients` the unrounded values
+/// they were rounded from, in natural order. Walking each block from the
+/// highest frequency down, every nonzero AC coefficient is tried one step
+/// closer to zero and at zero, and the level with the lowest cost
+/// `distortion + lambda * bits` is kept. DC coefficients are left alone.
+///
+/// Distortion is the squared error after dequantization with `table`, which
+/// the orthonormal DCT carries over to the samples, divided by the squared DC
+/// step of the table, so `lambda` is in squared DC steps per bit and holds
+/// across qualities. For a uniform quantizer at high rate the slope of
+/// distortion against rate is ln 2 / 6 of a squared step per bit. Bits are
+/// counted with the code lengths of `codebook`, so a change is only made if
+/// the codebook can code the result.
+pub fn optimize(
+    mut blocks: ArrayViewMut2<i64>,
+    quotients: ArrayView2<f64>,
+    table: &[i64; 64],
+    lambda: f64,
+    codebook: &HuffmanTable,
+) {
+    let weights: [f64; 64] = std::array::from_fn(|i| (table[i] * table[i]) as f64);
+    let step = weights[0];
+    for (mut block, exact) in blocks.rows_mut().into_iter().zip(quotients.rows()) {
+        let Some(mut bits) = block_bits(block.view(), codebook) else {
+            continue;
+        };
+
+        for i in (1..64).rev() {
+            let level = block[i];
+            if level == 0 {
+                continue;
+            }
+
+            let k = SCAN_ORDER_TABLE[i];
+            let error = |level: i64| (exact[k] - level as f64).powi(2) * weights[k] / step;
+            let mut best = (error(level) + lambda * bits as f64, level, bits);
+            for candidate in [level - level.signum(), 0] {
+                block[i] = candidate;
+                if let Some(n) = block_bits(block.view(), codebook) {
+                    let cost = error(candidate) + lambda * n as f64;
+                    if cost < best.0 {
+                        best = (cost, candidate, n);
+                    }
+                }
+            }
+
+            block[i] = best.1;
+            bits = best.2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dct, scale_quantization_table, zigzag_order, LUMA_QUANTIZATION_TABLE};
+
+    /// Quantized and zigzagged blocks of noise, with the unrounded quotients
+    /// they were rounded from.
+    fn blocks(table: &[i64; 64]) -> (Array2<i64>, Array2<f64>) {
+        let mut seed = 1u32;
+        let mut blocks = Array2::from_shape_fn((32, 64), |(_, i)| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            // Noise on a gradient leaves some small AC coefficients to drop.
+            (i as i64 * 2 + (seed >> 16) as i64 % 48).min(255)
+        });
+        let quotients = dct::fdct_quotients(blocks.view(), table);
+        dct::fdct(blocks.view_mut(), table);
+        zigzag_order(blocks.view_mut());
+        (blocks, quotients)
+    }
+
+    /// Distortion of a zigzagged block in squared DC steps, as [`optimize`]
+    /// measures it.
+    fn distortion(block: ArrayView1<i64>, exact: ArrayView1<f64>, table: &[i64; 64]) -> f64 {
+        let step = (table[0] * table[0]) as f64;
+        (0..64)
+            .map(|i| {
+                let k = SCAN_ORDER_TABLE[i];
+                (exact[k] - block[i] as f64).powi(2) * (table[k] * table[k]) as f64 / step
+            })
+            .sum()
+    }
+
+    #[test]
+    fn optimize_trades_distortion_for_bits() {
+        let table = scale_quantization_table(&LUMA_QUANTIZATION_TABLE, 50);
+        let codebook = HuffmanTable::new().unwrap();
+        let (original, quotients) = blocks(&table);
+
+        let mut saved = 0;
+        for lambda in [0.0, 0.12, 1.0] {
+            let mut optimized = original.clone();
+            optimize(
+                optimized.view_mut(),
+                quotients.view(),
+                &table,
+                lambda,
+                &codebook,
+            );
+
+            for ((before, after), exact) in original
+                .rows()
+                .into_iter()
+                .zip(optimized.rows())
+                .zip(quotients.rows())
+            {
+                let rate = |block| block_bits(block, &codebook).unwrap() as f64;
+                let (bits, new_bits) = (rate(before), rate(after));
+                assert!(new_bits <= bits);
+
+                // Levels only move towards zero, and no further than the
+                // bits they save are worth.
+                assert_eq!(before[0], after[0]);
+                for (&b, &a) in before.iter().zip(&after) {
+                    assert!(a == 0 || a == b || a == b - b.signum());
+                }
+                let cost = |block, bits| distortion(block, exact, &table) + lambda * bits;
+                assert!(cost(after, new_bits) <= cost(before, bits) + 1e-9);
+                saved += (bits - new_bits) as usize;
+            }
+
+            // Without a price on bits the rounded levels are already optimal.
+            if lambda == 0.0 {
+                assert_eq!(optimized, original);
+            }
+        }
+        assert!(saved > 0);
+    }
+}
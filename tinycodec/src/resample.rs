@@ -0,0 +1,146 @@
+use clap::ValueEnum;
+use ndarray::prelude::*;
+
+/// Filter used to reduce the chroma planes to the coded resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Downsample {
+    /// Keep the top-left sample of each group, bit-exact with the original encoder.
+    Decimate,
+    /// Average every sample of each group.
+    Box,
+}
+
+/// Filter used to bring the chroma planes back to full resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Upsample {
+    /// Repeat each sample, bit-exact with the original decoder.
+    Replicate,
+    /// Interpolate between the centres of neighbouring samples, as in
+    /// libjpeg's "fancy" upsampling.
+    Bilinear,
+}
+
+/// Reduce a plane to `height` x `width` samples.
+///
+/// The plane must be an exact multiple of the target size in each direction.
+pub fn downsample(
+    plane: ArrayView2<u8>,
+    height: usize,
+    width: usize,
+    filter: Downsample,
+) -> Array2<u8> {
+    if height == 0 || width == 0 {
+        return Array2::zeros((height, width));
+    }
+
+    let (h, w) = plane.dim();
+    let (sy, sx) = (h / height, w / width);
+
+    match filter {
+        Downsample::Decimate => {
+            Array2::from_shape_fn((height, width), |(i, j)| plane[[i * sy, j * sx]])
+        }
+        Downsample::Box => {
+            let n = (sy * sx) as u32;
+            Array2::from_shape_fn((height, width), |(i, j)| {
+                let sum: u32 = plane
+                    .slice(s![i * sy..(i + 1) * sy, j * sx..(j + 1) * sx])
+                    .iter()
+                    .map(|&p| p as u32)
+                    .sum();
+                ((sum + n / 2) / n) as u8
+            })
+        }
+    }
+}
+
+/// Expand a plane decimated by `(sy, sx)` to `height` x `width` samples.
+///
+/// `height` and `width` may be smaller than the full expanded size, in which
+/// case the result is cropped at the bottom and right.
+pub fn upsample(
+    plane: ArrayView2<u8>,
+    (sy, sx): (usize, usize),
+    height: usize,
+    width: usize,
+    filter: Upsample,
+) -> Array2<u8> {
+    match filter {
+        Upsample::Replicate => {
+            Array2::from_shape_fn((height, width), |(i, j)| plane[[i / sy, j / sx]])
+        }
+        Upsample::Bilinear => {
+            let rows = taps(height, sy, plane.nrows());
+            let cols = taps(width, sx, plane.ncols());
+            let scale = (4 * sy * sx) as u32;
+
+            Array2::from_shape_fn((height, width), |(i, j)| {
+                let (i0, i1, wy) = rows[i];
+                let (j0, j1, wx) = cols[j];
+                let (wy0, wx0) = (2 * sy as u32 - wy, 2 * sx as u32 - wx);
+                let sum = (plane[[i0, j0]] as u32 * wx0 + plane[[i0, j1]] as u32 * wx) * wy0
+                    + (plane[[i1, j0]] as u32 * wx0 + plane[[i1, j1]] as u32 * wx) * wy;
+                ((sum + scale / 2) / scale) as u8
+            })
+        }
+    }
+}
+
+/// Interpolation taps along one axis for centred bilinear upsampling.
+///
+/// Output sample `i` sits at `(2i + 1 - s) / 2s` in input coordinates, so
+/// each entry holds the two neighbouring input indices (clamped to the edge)
+/// and the weight of the second one out of `2s`.
+fn taps(len: usize, s: usize, input_len: usize) -> Vec<(usize, usize, u32)> {
+    let last = input_len.saturating_sub(1);
+    (0..len)
+        .map(|i| {
+            let p = (2 * i + 1) as isize - s as isize;
+            let k = p.div_euclid(2 * s as isize);
+            let frac = p.rem_euclid(2 * s as isize) as u32;
+            let k0 = k.clamp(0, last as isize) as usize;
+            let k1 = (k + 1).clamp(0, last as isize) as usize;
+            (k0, k1, frac)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_plane_round_trip() {
+        let plane = Array2::from_elem((24, 40), 77u8);
+        for (sy, sx) in [(1, 1), (1, 2), (2, 2)] {
+            for down in [Downsample::Decimate, Downsample::Box] {
+                for up in [Upsample::Replicate, Upsample::Bilinear] {
+                    let small = downsample(plane.view(), 24 / sy, 40 / sx, down);
+                    assert_eq!(upsample(small.view(), (sy, sx), 24, 40, up), plane);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn box_rounds_the_group_mean() {
+        let plane = array![[1, 2, 9, 9], [3, 4, 9, 10]];
+        assert_eq!(
+            downsample(plane.view(), 1, 2, Downsample::Box),
+            array![[3, 9]]
+        );
+        assert_eq!(
+            downsample(plane.view(), 1, 2, Downsample::Decimate),
+            array![[1, 9]]
+        );
+    }
+
+    #[test]
+    fn bilinear_interpolates_between_centres() {
+        let plane = array![[0, 80]];
+        assert_eq!(
+            upsample(plane.view(), (1, 2), 1, 4, Upsample::Bilinear),
+            array![[0, 20, 60, 80]]
+        );
+    }
+}
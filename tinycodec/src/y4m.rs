@@ -0,0 +1,276 @@
+use crate::color::Range;
+use crate::header::{Chroma, Header};
+use crate::Planes;
+use anyhow::{anyhow, Result};
+use ndarray::prelude::*;
+use std::io::{BufRead, Write};
+use std::str;
+
+/// Magic bytes at the start of every YUV4MPEG2 stream.
+pub const MAGIC: &[u8; 10] = b"YUV4MPEG2 ";
+
+/// Reader of 8-bit YUV4MPEG2 streams.
+///
+/// The stream header is a line of space-separated parameters, each a letter
+/// followed by its value. Every frame is a `FRAME` line followed by the Y, U
+/// and V planes in raster order, with the chroma planes rounded up to whole
+/// samples. Monochrome streams only have the Y plane.
+pub struct Y4mReader<R> {
+    reader: R,
+    pub width: usize,
+    pub height: usize,
+    pub frame_rate: (u32, u32),
+    pub chroma: Chroma,
+    /// Range of the planes, if the stream has an `XCOLORRANGE` tag.
+    pub range: Option<Range>,
+    header_size: usize,
+}
+
+impl<R> Y4mReader<R>
+where
+    R: BufRead,
+{
+    /// Read the stream header.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        let header_size = line.len();
+        let line = line
+            .strip_prefix(MAGIC)
+            .and_then(|line| line.strip_suffix(b"\n"))
+            .ok_or_else(|| anyhow!("Not a Y4M stream"))?;
+
+        let (mut width, mut height, mut frame_rate) = (0, 0, None);
+        let mut chroma = Chroma::Yuv420;
+        let mut range = None;
+        for param in str::from_utf8(line)?.split(' ').filter(|p| !p.is_empty()) {
+            let (tag, value) = param.split_at(1);
+            match tag {
+                "W" => width = value.parse()?,
+                "H" => height = value.parse()?,
+                "F" => {
+                    let (num, den) = value
+                        .split_once(':')
+                        .ok_or_else(|| anyhow!("Invalid Y4M frame rate: {}", value))?;
+                    frame_rate = Some((num.parse()?, den.parse()?));
+                }
+                "C" => {
+                    chroma = match value {
+                        "420" | "420jpeg" | "420paldv" | "420mpeg2" => Chroma::Yuv420,
+                        "422" => Chroma::Yuv422,
+                        "444" => Chroma::Yuv444,
+                        "mono" => Chroma::Gray,
+                        _ => return Err(anyhow!("Unsupported Y4M colour space: {}", value)),
+                    }
+                }
+                "X" => match value {
+                    "COLORRANGE=FULL" => range = Some(Range::Full),
+                    "COLORRANGE=LIMITED" => range = Some(Range::Limited),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        let frame_rate = frame_rate
+            .filter(|&(num, den)| num > 0 && den > 0)
+            .ok_or_else(|| anyhow!("Y4M stream has no frame rate"))?;
+        if width == 0 || height == 0 {
+            return Err(anyhow!("Y4M stream has no frame size"));
+        }
+
+        Ok(Y4mReader {
+            reader,
+            width,
+            height,
+            frame_rate,
+            chroma,
+            range,
+            header_size,
+        })
+    }
+
+    /// Size in samples of the chroma planes.
+    fn chroma_size(&self) -> (usize, usize) {
+        let (sy, sx) = self.chroma.subsampling();
+        match self.chroma {
+            Chroma::Gray => (0, 0),
+            _ => (self.height.div_ceil(sy), self.width.div_ceil(sx)),
+        }
+    }
+
+    /// Number of frames in a file of `file_size` bytes, assuming no frame
+    /// has parameters on its `FRAME` line.
+    pub fn frame_count(&self, file_size: u64) -> usize {
+        let (ch, cw) = self.chroma_size();
+        let frame_size = b"FRAME\n".len() + self.height * self.width + 2 * ch * cw;
+        file_size.saturating_sub(self.header_size as u64) as usize / frame_size
+    }
+
+    /// Read the next frame, or `None` at the end of the stream. Monochrome
+    /// frames have empty U and V planes.
+    pub fn read_frame(&mut self) -> Result<Option<Planes>> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.starts_with(b"FRAME") || !line.ends_with(b"\n") {
+            return Err(anyhow!("Invalid Y4M frame header"));
+        }
+
+        let (height, width) = (self.height, self.width);
+        let (ch, cw) = self.chroma_size();
+        let mut read_plane = |height, width| -> Result<Array2<u8>> {
+            let mut samples = vec![0; height * width];
+            self.reader.read_exact(&mut samples)?;
+            Ok(Array2::from_shape_vec((height, width), samples)?)
+        };
+        let y = read_plane(height, width)?;
+        let u = read_plane(ch, cw)?;
+        let v = read_plane(ch, cw)?;
+
+        Ok(Some(Planes { y, u, v }))
+    }
+}
+
+/// Writer of 8-bit YUV4MPEG2 streams in the chroma layout of a tinycodec
+/// stream, with its colour range as an `XCOLORRANGE` tag.
+pub struct Y4mWriter<W> {
+    out: W,
+    width: usize,
+    height: usize,
+    chroma: Chroma,
+}
+
+impl<W> Y4mWriter<W>
+where
+    W: Write,
+{
+    /// Write the stream header for frames described by `header`.
+    pub fn new(mut out: W, header: &Header) -> Result<Self> {
+        let (num, den) = header.frame_rate;
+        let colour_space = match header.chroma {
+            Chroma::Yuv420 => "420jpeg",
+            Chroma::Yuv422 => "422",
+            Chroma::Yuv444 => "444",
+            Chroma::Gray => "mono",
+        };
+        let range = match header.range {
+            Range::Full => "FULL",
+            Range::Limited => "LIMITED",
+        };
+        writeln!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C{} XCOLORRANGE={}",
+            header.width, header.height, num, den, colour_space, range
+        )?;
+
+        Ok(Y4mWriter {
+            out,
+            width: header.width,
+            height: header.height,
+            chroma: header.chroma,
+        })
+    }
+
+    /// Write one frame of decoded planes, cropped to the frame size.
+    pub fn write_frame(&mut self, planes: &Planes) -> Result<()> {
+        self.out.write_all(b"FRAME\n")?;
+
+        let mut write_plane = |plane: &Array2<u8>, height: usize, width: usize| {
+            let samples = plane.slice(s![..height, ..width]);
+            self.out
+                .write_all(&samples.iter().copied().collect::<Vec<_>>())
+        };
+        write_plane(&planes.y, self.height, self.width)?;
+        if self.chroma != Chroma::Gray {
+            let (sy, sx) = self.chroma.subsampling();
+            let (ch, cw) = (self.height.div_ceil(sy), self.width.div_ceil(sx));
+            write_plane(&planes.u, ch, cw)?;
+            write_plane(&planes.v, ch, cw)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush the underlying writer.
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Matrix;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let (width, height) = (37, 23);
+        for chroma in [Chroma::Yuv420, Chroma::Yuv422, Chroma::Yuv444] {
+            let header = Header {
+                width,
+                height,
+                frame_rate: (30000, 1001),
+                frame_count: 2,
+                gop: 1,
+                chroma,
+                matrix: Matrix::Bt601,
+                range: Range::Limited,
+                index_offset: 0,
+                luma_table: [1; 64],
+                chroma_table: [1; 64],
+                huffman: None,
+                dc_prediction: true,
+                restart_interval: None,
+                skip_blocks: false,
+                frame_quality: false,
+            };
+
+            // Padded planes as they come out of the decoder, with a distinct
+            // value in every sample that survives the crop.
+            let (ph, pw) = header.padded_size();
+            let (ch, cw) = header.chroma_size();
+            let frames: Vec<Planes> = (0..2usize)
+                .map(|frame| {
+                    let plane = |height, width, offset| {
+                        Array2::from_shape_fn((height, width), |(y, x)| {
+                            (frame * 97 + offset + 7 * y + x) as u8
+                        })
+                    };
+                    Planes {
+                        y: plane(ph, pw, 0),
+                        u: plane(ch, cw, 50),
+                        v: plane(ch, cw, 150),
+                    }
+                })
+                .collect();
+
+            let mut writer = Y4mWriter::new(Vec::new(), &header).unwrap();
+            for planes in &frames {
+                writer.write_frame(planes).unwrap();
+            }
+            let data = writer.out;
+
+            let mut reader = Y4mReader::new(Cursor::new(&data)).unwrap();
+            assert_eq!((reader.width, reader.height), (width, height));
+            assert_eq!(reader.frame_rate, header.frame_rate);
+            assert_eq!(reader.chroma, chroma);
+            assert_eq!(reader.range, Some(Range::Limited));
+            assert_eq!(reader.frame_count(data.len() as u64), 2);
+
+            let (sy, sx) = chroma.subsampling();
+            let (ch, cw) = (height.div_ceil(sy), width.div_ceil(sx));
+            for planes in &frames {
+                let read = reader.read_frame().unwrap().unwrap();
+                assert_eq!(read.y, planes.y.slice(s![..height, ..width]));
+                assert_eq!(read.u, planes.u.slice(s![..ch, ..cw]));
+                assert_eq!(read.v, planes.v.slice(s![..ch, ..cw]));
+            }
+            assert!(reader.read_frame().unwrap().is_none());
+        }
+    }
+}